@@ -3,23 +3,32 @@
 //!
 
 
-use core::slice;
-use std::ptr::null_mut;
+use std::{sync::atomic::Ordering, time::{Duration, Instant}};
 
 use log::{*};
-use vst3_sys::{base::kResultOk, vst::{AudioBusBuffers, IAudioProcessor}};
-use crate::{audio::{Audio, AudioFormatInfo}, config::{ASIO_BUFFER_SIZE, ASIO_SAMPLE_RATE}, error::Error, host::Host, instance::Instance, instrument::Instrument, registry::Registry, time::{SharedTimingContext, Timing}, window::Window};
+use vst3_sys::vst::{ProcessModes, RestartFlags};
+use crate::{audio::{Audio, AudioBackendType, AudioFormatInfo}, config::{ASIO_BUFFER_SIZE, ASIO_SAMPLE_RATE, HEADLESS_RUN_TIME_SECONDS, IDLE_INTERVAL_MS, NULL_BUFFER_SIZE, NULL_DEVICE_NAME, NULL_SAMPLE_RATE, STRESS_DRAIN_TIME_MS}, edit_controller::ParameterEdit, error::Error, events::OutputEvent, host::Host, instance::Instance, instrument::Instrument, midi_input::{MidiInput, MidiTimingMode}, preset::PresetMetaInfo, registry::Registry, render::{OfflineRenderer, RenderSettings}, stress::{StressSettings, StressTest}, time::{SharedTimingContext, Timing}};
+
+#[cfg(windows)]
+use crate::window::Window;
 
 pub struct Application {
     registry: Registry,
     host: Host,
     timing: SharedTimingContext,
     audio: Option<Audio>,
+    midi_input: Option<MidiInput>,
     instance: Option<Instance>,
     instrument: Option<Instrument>,
-    window: Option<Box<Window>>
+    #[cfg(windows)]
+    window: Option<Box<Window>>,
+    headless_run_time: Duration,
+    output_event_callback: Option<OutputEventCallback>,
+    parameter_edits: Vec<ParameterEdit>
 }
 
+pub type OutputEventCallback = Box<dyn FnMut(&OutputEvent)>;
+
 impl Application {
     pub fn new() -> Result<Self, Error> {
         trace!("new");
@@ -28,6 +37,11 @@ impl Application {
         registry.init()?;
 
         let host = Host::new()?;
+        crate::connection::register_interfaces(&host);
+        crate::message::register_interfaces(&host);
+        crate::midi_mapping::register_interfaces(&host);
+        #[cfg(windows)]
+        crate::view::register_interfaces(&host);
 
         let timing = Timing::new()?;
 
@@ -36,9 +50,14 @@ impl Application {
             host,
             timing,
             audio: None,
+            midi_input: None,
             instance: None,
             instrument: None,
-            window: None
+            #[cfg(windows)]
+            window: None,
+            headless_run_time: Duration::from_secs(HEADLESS_RUN_TIME_SECONDS),
+            output_event_callback: None,
+            parameter_edits: Vec::new()
         };
 
         Ok(app)
@@ -46,6 +65,7 @@ impl Application {
 
     pub fn dispose(&mut self) {
 
+        let _ = self.close_midi_input();
         let _ = self.unload_instrument();
         let _ = self.close_window();
         let _ = self.close_audio();
@@ -75,10 +95,12 @@ impl Application {
 
         trace!("create instrument");
 
-        let instrument = Instrument::new(&instance, &self.host, self.audio.as_mut().unwrap())?;
+        let audio_format = self.audio.as_ref().unwrap().get_format().clone();
+        let instrument = Instrument::new(&instance, &self.host, &audio_format, ProcessModes::kRealtime as i32)?;
 
         trace!("create window");
 
+        #[cfg(windows)]
         match self.window.as_mut() {
             Some(window) => {
                 trace!("create view");
@@ -110,6 +132,7 @@ impl Application {
             None => {}
         };
 
+        #[cfg(windows)]
         match self.window.as_mut() {
             Some(window) => {
                 trace!("detach view");
@@ -129,6 +152,7 @@ impl Application {
         match self.instrument.take() {
             Some(instrument) => {
                 trace!("dispose instrument");
+                instrument.get_process_stats().report();
                 /*
                 match Arc::try_unwrap(instrument) {
                     Ok(instrument_lock) => {
@@ -174,6 +198,7 @@ impl Application {
         Ok(())
     }
 
+    #[cfg(windows)]
     pub fn create_window(&mut self) -> Result<(), Error> {
         trace!("create window");
         let mut window = Window::new("Keystone", 800, 600, true)?;
@@ -183,8 +208,14 @@ impl Application {
         Ok(())
     }
 
+    #[cfg(not(windows))]
+    pub fn create_window(&mut self) -> Result<(), Error> {
+        Err(Error::from("windows are not supported on this platform"))
+    }
+
     pub fn close_window(&mut self) -> Result<(), Error> {
         trace!("close window");
+        #[cfg(windows)]
         match self.window.take() {
             Some(mut window) => {
                 window.stop_timer()?;
@@ -195,14 +226,25 @@ impl Application {
         Ok(())
     }
 
+    #[cfg(windows)]
+    fn has_window(&self) -> bool {
+        self.window.is_some()
+    }
+
+    #[cfg(not(windows))]
+    fn has_window(&self) -> bool {
+        false
+    }
+
     pub fn set_active(&mut self, active: bool) -> Result<(), Error> {
         trace!("set active");
 
         self.set_processing(false)?;
 
-        match &self.instance {
-            Some(instance) => {
-                let _ = instance.set_active(active);
+        // the instrument keeps track of it to reactivate after a restart
+        match self.instrument.as_mut() {
+            Some(instrument) => {
+                let _ = instrument.set_active(active);
             },
             None => {}
         };
@@ -234,15 +276,17 @@ impl Application {
         Ok(())
     }
 
-    pub fn create_audio(&mut self, name: &str) -> Result<(), Error> {
+    pub fn create_audio(&mut self, backend_type: AudioBackendType, name: &str) -> Result<(), Error> {
         trace!("start audio");
 
         let _ = self.close_audio();
 
-        let sample_rate = ASIO_SAMPLE_RATE;
-        let buffer_size = ASIO_BUFFER_SIZE;
+        let (sample_rate, buffer_size) = match backend_type {
+            AudioBackendType::Asio => (ASIO_SAMPLE_RATE, ASIO_BUFFER_SIZE),
+            AudioBackendType::Null => (NULL_SAMPLE_RATE, NULL_BUFFER_SIZE)
+        };
 
-        let audio = Audio::new(name, sample_rate, buffer_size)?;
+        let audio = Audio::new(backend_type, name, sample_rate, buffer_size)?;
 
         self.audio = Some(audio);
 
@@ -263,6 +307,39 @@ impl Application {
         Ok(())
     }
 
+    pub fn set_midi_timing_mode(&mut self, mode: MidiTimingMode) {
+        match self.instrument.as_mut() {
+            Some(instrument) => {
+                instrument.set_midi_timing_mode(mode);
+            },
+            None => {}
+        };
+    }
+
+    pub fn create_midi_input(&mut self, name: &str) -> Result<(), Error> {
+        trace!("create midi input");
+
+        let _ = self.close_midi_input();
+
+        self.midi_input = Some(MidiInput::new(name)?);
+
+        Ok(())
+    }
+
+    pub fn close_midi_input(&mut self) -> Result<(), Error> {
+        trace!("close midi input");
+
+        match self.midi_input.take() {
+            Some(mut midi_input) => {
+                midi_input.stop()?;
+                midi_input.dispose();
+            },
+            None => {}
+        };
+
+        Ok(())
+    }
+
     pub fn get_audio_format(&self) -> Option<&AudioFormatInfo> {
         match self.audio.as_ref() {
             Some(audio) => {
@@ -272,193 +349,265 @@ impl Application {
         }
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        trace!("run");
+    pub fn render(&mut self, settings: &RenderSettings) -> Result<(), Error> {
+        trace!("render");
 
-        let mut context = match self.instrument.as_mut() {
-            Some(instrument) => {
-                Some(instrument.get_context().clone())
-            },
-            None => None
-        };
+        if self.instrument.is_some() {
+            return Err(Error::from("cannot render while an instrument is loaded"));
+        }
 
-        if self.audio.is_some() {
-            self.audio.as_mut().unwrap().start(move |callback_info| {
+        OfflineRenderer::render(&mut self.registry, &self.host, settings)
+    }
 
-                //trace!("audio callback");
+    /// Runs the stress test on the null backend, see `StressTest`.
+    pub fn stress(&mut self, settings: &StressSettings) -> Result<(), Error> {
+        trace!("stress");
 
-                match context.as_mut() {
-                    Some(context) => {
-                        match context.lock() {
-                            Ok(mut context) => {
-                                let audio_processor_intf = &context.audio_processor.audio_processor.clone();
-                                let process_data = &mut context.process_data;
+        if self.instrument.is_some() {
+            return Err(Error::from("cannot run the stress test while an instrument is loaded"));
+        }
 
-                                Self::process_data(audio_processor_intf, process_data, callback_info);
+        self.create_audio(AudioBackendType::Null, NULL_DEVICE_NAME)?;
+        self.load_instrument(&settings.class_id)?;
 
-                                /*
-                                process_data.num_samples = callback_info.buffer_size as i32;
+        let audio_format = self.get_audio_format().unwrap().clone();
+        let block_time = Duration::from_secs_f64(audio_format.buffer_size as f64 / audio_format.sample_rate);
 
-                                let audio_buffers = [
-                                    callback_info.buffer0,
-                                    callback_info.buffer1
-                                ].as_mut_ptr();
+        let (stress_test, stats) = match self.instrument.as_ref() {
+            Some(instrument) => (StressTest::start(instrument, settings, audio_format.buffer_size)?, instrument.get_process_stats()),
+            None => {
+                return Err(Error::from("no instrument loaded"));
+            }
+        };
 
-                                let mut output_buffers = AudioBusBuffers {
-                                    num_channels: 2,
-                                    silence_flags: 0x0,
-                                    buffers: audio_buffers
-                                };
+        // keep processing until the queues are drained
+        self.set_headless_run_time(settings.run_time + Duration::from_millis(STRESS_DRAIN_TIME_MS));
+        let run_result = self.run();
+        let count = stress_test.join();
 
-                                process_data.outputs = &mut output_buffers;
+        let _ = self.unload_instrument();
+        let _ = self.close_audio();
 
-                                let result = unsafe {
-                                    audio_processor_intf.process(process_data as *mut _)
-                                };
+        run_result?;
+        StressTest::report(&count?, &stats, block_time)
+    }
 
-                                if result != kResultOk {
-                                    trace!("audio processor processing failed");
-                                }
-                                */
-                            },
-                            Err(_) => {}
-                        };
-                    },
-                    None => {}
-                }
+    pub fn save_state(&mut self, path: &str) -> Result<(), Error> {
+        trace!("save state");
 
-            })?
+        match self.instrument.as_mut() {
+            Some(instrument) => instrument.save_state(path),
+            None => Err(Error::from("no instrument loaded"))
         }
+    }
 
-        if self.window.is_some() {
-            self.window.as_ref().unwrap().event_loop();
-        }
+    pub fn load_state(&mut self, path: &str) -> Result<(), Error> {
+        trace!("load state");
 
-        if self.audio.is_some() {
-            let _ = self.audio.as_mut().unwrap().stop();
+        match self.instrument.as_mut() {
+            Some(instrument) => instrument.load_state(path),
+            None => Err(Error::from("no instrument loaded"))
         }
-
-        Ok(())
     }
 
-    fn process_data(audio_processor_intf: &vst3_com::VstPtr<dyn IAudioProcessor>, process_data: &mut vst3_sys::vst::ProcessData, callback_info: &crate::audio::AudioCallbackInfo) {
-        process_data.num_samples = callback_info.buffer_size as i32;
+    pub fn save_preset(&mut self, path: &str, meta_info: &PresetMetaInfo) -> Result<(), Error> {
+        trace!("save preset");
 
-        let mut audio_buffers = [
-            callback_info.buffer0,
-            callback_info.buffer1
-        ];
+        match self.instrument.as_mut() {
+            Some(instrument) => instrument.save_preset(path, meta_info),
+            None => Err(Error::from("no instrument loaded"))
+        }
+    }
 
-        let audio_buffers_ptr = audio_buffers.as_mut_ptr();
+    pub fn load_preset(&mut self, path: &str) -> Result<(), Error> {
+        trace!("load preset");
 
-        let mut output_buffers = AudioBusBuffers {
-            num_channels: 2,
-            silence_flags: 0x0,
-            buffers: audio_buffers_ptr
-        };
+        match self.instrument.as_mut() {
+            Some(instrument) => instrument.load_preset(path),
+            None => Err(Error::from("no instrument loaded"))
+        }
+    }
 
-        process_data.outputs = &mut output_buffers;
+    /// Receives the events the processor outputs, e.g. MIDI from arpeggiators.
+    pub fn set_output_event_callback<F>(&mut self, callback: F)
+    where
+        F: 'static + FnMut(&OutputEvent)
+    {
+        self.output_event_callback = Some(Box::new(callback));
+    }
 
-        let mut input_buffers = AudioBusBuffers {
-            num_channels: 0,
-            silence_flags: 0x0,
-            buffers: null_mut()
+    /// Reverts the most recent GUI edit gesture, returns false if there is none.
+    pub fn undo_parameter_edit(&mut self) -> Result<bool, Error> {
+        let edit = match self.parameter_edits.pop() {
+            Some(edit) => edit,
+            None => {
+                return Ok(false);
+            }
         };
 
-        process_data.inputs = &mut input_buffers;
-
-        let result = unsafe { audio_processor_intf.process(process_data as *mut _) };
-        if result != kResultOk {
-            trace!("audio processor processing failed");
+        match self.instrument.as_mut() {
+            Some(instrument) => {
+                instrument.set_parameter(edit.id, edit.begin_value, 0)?;
+                Ok(true)
+            },
+            None => Err(Error::from("no instrument loaded"))
         }
+    }
 
-        {
-            let buffer_size = callback_info.buffer_size;
+    fn process_outputs(instrument: &mut Option<Instrument>, output_event_callback: &mut Option<OutputEventCallback>, parameter_edits: &mut Vec<ParameterEdit>) {
+        match instrument.as_mut() {
+            Some(instrument) => {
+                let restart_flags = instrument.take_restart_flags();
+                if restart_flags != 0 {
+                    Self::restart_component(instrument, restart_flags);
+                }
 
-            for buffer_ptr in audio_buffers {
-                let in_buffer = unsafe { slice::from_raw_parts_mut(buffer_ptr as *mut f32, buffer_size) };
-                let out_buffer = unsafe { slice::from_raw_parts_mut(buffer_ptr as *mut u32, buffer_size) };
+                parameter_edits.extend(instrument.take_completed_edits());
 
-                for i in 0..buffer_size {
-                    let a = in_buffer[i];
+                instrument.dispatch_messages();
 
-                    let s = ((if a >= 0.0 {
-                        a
-                    } else {
-                        2.0+a
-                    }) * 32767.0) as u32;
+                let events = instrument.process_outputs();
+                match output_event_callback.as_mut() {
+                    Some(callback) => {
+                        for event in &events {
+                            callback(event);
+                        }
+                    },
+                    None => {}
+                };
+            },
+            None => {}
+        };
+    }
 
-                    let s32 = (s & 0xffff) << 16;
+    fn restart_component(instrument: &mut Instrument, flags: i32) {
+        trace!("restart component {:#x}", flags);
 
-                    out_buffer[i] = s32;
-                }
-            }
+        if flags & (RestartFlags::kParamValuesChanged as i32 | RestartFlags::kParamTitlesChanged as i32) != 0 {
+            // nothing is cached on the host side yet, just report the new state
+            debug!("plugin parameters changed, {} parameters", instrument.get_parameter_count());
+        }
 
-            /*
+        // the window and the view stay attached while the processor is reconfigured
+        match instrument.restart_component(flags) {
+            Ok(_) => {},
+            Err(e) => {
+                error!("{}", e.message());
+            }
+        };
 
-            let buffer_size = callback_info.buffer_size;
-            let output_buffer0 = unsafe { slice::from_raw_parts_mut(callback_info.buffer0 as *mut SampleType, buffer_size) };
-            let output_buffer1 = unsafe { slice::from_raw_parts_mut(callback_info.buffer1 as *mut SampleType, buffer_size) };
+        let handled_flags = RestartFlags::kParamValuesChanged as i32 | RestartFlags::kParamTitlesChanged as i32
+            | RestartFlags::kReloadComponent as i32 | RestartFlags::kIoChanged as i32 | RestartFlags::kLatencyChanged as i32
+            | RestartFlags::kMidiCCAssignmentChanged as i32;
+        let unhandled = flags & !handled_flags;
+        if unhandled != 0 {
+            trace!("restart component flags {:#x} ignored", unhandled);
+        }
+    }
 
-            let mut w: f64 = 0.0;
-            let volume = 0.5;
+    pub fn set_headless_run_time(&mut self, run_time: Duration) {
+        self.headless_run_time = run_time;
+    }
 
-            for i in 0..buffer_size {
-                w += 0.012;
-                if w >= 1.0 { w -= 1.0; }
+    pub fn run(&mut self) -> Result<(), Error> {
+        trace!("run");
 
-                let a = f64::sin(w * 3.1415 * 2.0) * volume;
-                //let a = (w * 2.0 - 1.0) * volume;
+        let mut context = self.instrument.as_mut().map(|instrument| {
+            (instrument.get_context().clone(), instrument.get_process_stats())
+        });
 
-                let s = ((if a >= 0.0 {
-                    a
-                } else {
-                    2.0+a
-                }) * 32767.0) as SampleType;
+        if let Some(audio) = self.audio.as_mut() {
+            audio.start(move |callback_info| {
 
-                (((a + 1.0) * 32767.0) as SampleType);
+                //trace!("audio callback");
 
-                let s1 = (s & 0xffff) << 16;
+                match context.as_mut() {
+                    Some((context, stats)) => {
+                        // the control thread holds the lock while reconfiguring the processor,
+                        // events and parameters are handed over through queues and never block
+                        match context.try_lock() {
+                            Ok(mut context) => {
+                                context.process(callback_info);
 
-                output_buffer0[i] = s1;
-                output_buffer1[i] = s1;
-            }
-            */
+                                /*
+                                process_data.num_samples = callback_info.buffer_size as i32;
 
-        }
+                                let audio_buffers = [
+                                    callback_info.buffer0,
+                                    callback_info.buffer1
+                                ].as_mut_ptr();
 
-        /*
-        type SampleType = u32;
+                                let mut output_buffers = AudioBusBuffers {
+                                    num_channels: 2,
+                                    silence_flags: 0x0,
+                                    buffers: audio_buffers
+                                };
 
-        let buffer_size = callback_info.buffer_size;
-        let output_buffer0 = unsafe { slice::from_raw_parts_mut(callback_info.buffer0 as *mut SampleType, buffer_size) };
-        let output_buffer1 = unsafe { slice::from_raw_parts_mut(callback_info.buffer1 as *mut SampleType, buffer_size) };
+                                process_data.outputs = &mut output_buffers;
 
-        let mut w: f64 = 0.0;
-        let volume = 0.5;
+                                let result = unsafe {
+                                    audio_processor_intf.process(process_data as *mut _)
+                                };
 
-        for i in 0..buffer_size {
-            w += 0.012;
-            if w >= 1.0 { w -= 1.0; }
+                                if result != kResultOk {
+                                    trace!("audio processor processing failed");
+                                }
+                                */
+                            },
+                            Err(_) => {
+                                stats.skipped_blocks.fetch_add(1, Ordering::Relaxed);
+                                callback_info.clear();
+                            }
+                        };
+                    },
+                    None => {}
+                }
 
-            let a = f64::sin(w * 3.1415 * 2.0) * volume;
-            //let a = (w * 2.0 - 1.0) * volume;
+            })?
+        }
 
-            let s = ((if a >= 0.0 {
-                a
-            } else {
-                2.0+a
-            }) * 32767.0) as SampleType;
+        match (self.midi_input.as_mut(), self.instrument.as_ref()) {
+            (Some(midi_input), Some(instrument)) => {
+                let midi_input_queue = instrument.get_midi_input_queue();
+                midi_input.start(move |input_event| {
+                    if midi_input_queue.push(*input_event).is_err() {
+                        trace!("MIDI input queue overflow");
+                    }
+                })?;
+            },
+            _ => {}
+        };
 
-            (((a + 1.0) * 32767.0) as SampleType);
+        let idle_interval = Duration::from_millis(IDLE_INTERVAL_MS);
+
+        if self.has_window() {
+            #[cfg(windows)]
+            {
+                let instrument = &mut self.instrument;
+                let output_event_callback = &mut self.output_event_callback;
+                let parameter_edits = &mut self.parameter_edits;
+                self.window.as_ref().unwrap().event_loop(idle_interval, || {
+                    Self::process_outputs(instrument, output_event_callback, parameter_edits);
+                });
+            }
+        } else {
+            trace!("running headless for {:?}", self.headless_run_time);
+            let end_time = Instant::now() + self.headless_run_time;
+            while Instant::now() < end_time {
+                std::thread::sleep(idle_interval.min(end_time.saturating_duration_since(Instant::now())));
+                Self::process_outputs(&mut self.instrument, &mut self.output_event_callback, &mut self.parameter_edits);
+            }
+        }
 
-            let s1 = (s & 0xffff) << 16;
+        if let Some(midi_input) = self.midi_input.as_mut() {
+            let _ = midi_input.stop();
+        }
 
-            output_buffer0[i] = s1;
-            output_buffer1[i] = s1;
+        if let Some(audio) = self.audio.as_mut() {
+            let _ = audio.stop();
         }
-        */
 
+        Ok(())
     }
 
 }
@@ -0,0 +1,134 @@
+//!
+//! Null audio backend
+//!
+//! Drives the audio callback from a timer thread at the configured
+//! sample rate and block size. The rendered output is discarded, which
+//! allows running the processing path without any audio hardware.
+//!
+
+use log::{*};
+use std::{ffi::c_void, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread::JoinHandle, time::{Duration, Instant}};
+use crate::{audio::{AudioBackend, AudioCallback, AudioCallbackInfo, AudioFormatInfo, SampleFormat}, error::Error};
+
+const DEFAULT_BUFFER_SIZE: usize = 256;
+
+pub struct NullAudio {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    format: AudioFormatInfo
+}
+
+impl NullAudio {
+    fn run_timer(running: Arc<AtomicBool>, format: AudioFormatInfo, mut callback: AudioCallback) {
+        trace!("timer thread started");
+
+        let buffer_size = format.buffer_size;
+        let mut buffer0 = vec![0.0f32; buffer_size];
+        let mut buffer1 = vec![0.0f32; buffer_size];
+
+        let period = Duration::from_secs_f64(buffer_size as f64 / format.sample_rate);
+        let mut deadline = Instant::now();
+
+        while running.load(Ordering::Acquire) {
+
+            let audio_callback_info = AudioCallbackInfo {
+                buffer0: buffer0.as_mut_ptr() as *mut c_void,
+                buffer1: buffer1.as_mut_ptr() as *mut c_void,
+                buffer_size,
+                sample_format: format.sample_format,
+                time: Instant::now()
+            };
+
+            callback(&audio_callback_info);
+
+            deadline += period;
+
+            let now = Instant::now();
+            if deadline > now {
+                std::thread::sleep(deadline - now);
+            } else {
+                // callback took longer than a block, do not try to catch up
+                deadline = now;
+            }
+        }
+
+        trace!("timer thread stopped");
+    }
+}
+
+impl AudioBackend for NullAudio {
+    fn open(name: &str, configured_sample_rate: f64, configured_buffer_size: usize) -> Result<Self, Error> {
+        trace!("open '{}'", name);
+
+        if configured_sample_rate <= 0.0 {
+            return Err(Error::from("invalid sample rate"));
+        }
+
+        let buffer_size = if configured_buffer_size > 0 { configured_buffer_size } else { DEFAULT_BUFFER_SIZE };
+
+        let format = AudioFormatInfo {
+            sample_rate: configured_sample_rate,
+            num_channels: 2,
+            buffer_size,
+            sample_format: SampleFormat::Float32
+        };
+
+        trace!("null sample buffer size: {}", buffer_size);
+        trace!("null sample rate: {}", configured_sample_rate);
+
+        Ok(Self {
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+            format
+        })
+    }
+
+    fn start(&mut self, callback: AudioCallback) -> Result<(), Error> {
+        if self.thread.is_some() {
+            return Err(Error::from("audio already started"));
+        }
+
+        self.running.store(true, Ordering::Release);
+
+        let running = self.running.clone();
+        let format = self.format.clone();
+
+        let thread = match std::thread::Builder::new()
+            .name("null-audio".to_string())
+            .spawn(move || Self::run_timer(running, format, callback)) {
+            Ok(thread) => thread,
+            Err(_) => {
+                self.running.store(false, Ordering::Release);
+                return Err(Error::from("failed to start audio thread"));
+            }
+        };
+
+        self.thread = Some(thread);
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        trace!("stop");
+
+        self.running.store(false, Ordering::Release);
+
+        match self.thread.take() {
+            Some(thread) => {
+                thread.join().map_err(|_| Error::from("audio thread panicked"))?;
+            },
+            None => {}
+        };
+
+        Ok(())
+    }
+
+    fn dispose(&mut self) {
+        trace!("dispose");
+        let _ = self.stop();
+    }
+
+    fn get_format(&self) -> &AudioFormatInfo {
+        &self.format
+    }
+}
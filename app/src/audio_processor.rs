@@ -1,17 +1,29 @@
 
 use log::{*};
 use vst3_com::VstPtr;
-use vst3_sys::{base::kResultOk, vst::{BusDirections, Chord, FrameRate, IAudioProcessor, ProcessContext, ProcessModes, ProcessSetup, SymbolicSampleSizes}};
+use vst3_sys::{base::kResultOk, vst::{BusDirections, Chord, FrameRate, IAudioProcessor, ProcessContext, ProcessSetup, SymbolicSampleSizes}};
 
-use crate::{audio::{Audio, AudioFormatInfo}, error::Error, instance::Instance, instrument::ProcessContextFlags};
+use crate::{audio::AudioFormatInfo, error::Error, instance::Instance, instrument::ProcessContextFlags};
 
 const DEFAULT_AUDIO_BUFFER_SIZE: usize = 128;
 const DEFAULT_SAMPLE_RATE: f64 = 48000.0;
 
+const K_NO_TAIL: u32 = 0;
+const K_INFINITE_TAIL: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tail {
+    None,
+    Samples(usize),
+    Infinite
+}
+
 pub struct AudioContext {
     pub audio_format: AudioFormatInfo,
+    pub process_mode: i32,
     pub samples_per_block: usize,
     pub latency_samples: usize,
+    pub processing: bool,
     pub process_context: Box<ProcessContext>,
 }
 
@@ -27,7 +39,7 @@ impl Drop for AudioProcessor {
 }
 
 impl AudioProcessor {
-    pub fn new(instance: &Instance, audio: &Audio) -> Result<Self, Error> {
+    pub fn new(instance: &Instance, audio_format: &AudioFormatInfo, process_mode: i32) -> Result<Self, Error> {
         trace!("new");
 
         let audio_processor = instance.query_audio_processor_intf()?;
@@ -36,15 +48,15 @@ impl AudioProcessor {
         let samples_per_block = DEFAULT_AUDIO_BUFFER_SIZE;
         let latency_samples: usize = 0;
 
-        let audio_format = audio.get_format();
-
         let process_context = Self::create_process_context(audio_format)?;
 
         let context = AudioContext {
             samples_per_block,
             latency_samples,
+            processing: false,
             process_context: Box::new(process_context),
-            audio_format: audio_format.clone()
+            audio_format: audio_format.clone(),
+            process_mode
         };
 
         Ok(Self {
@@ -61,6 +73,19 @@ impl AudioProcessor {
         &self.context.audio_format
     }
 
+    pub fn get_process_mode(&self) -> i32 {
+        self.context.process_mode
+    }
+
+    pub fn is_processing(&self) -> bool {
+        self.context.processing
+    }
+
+    /// Latency reported by the processor when processing was last enabled.
+    pub fn get_latency(&self) -> usize {
+        self.context.latency_samples
+    }
+
     pub fn get_audio_processor_intf(&self) -> &VstPtr<dyn IAudioProcessor> {
         &self.audio_processor
     }
@@ -109,7 +134,7 @@ impl AudioProcessor {
         let audio_format = &self.context.audio_format;
 
         let mut processing_setup = ProcessSetup {
-            process_mode: ProcessModes::kRealtime as i32,
+            process_mode: self.context.process_mode,
             symbolic_sample_size: SymbolicSampleSizes::kSample32 as i32,
             max_samples_per_block: audio_format.buffer_size as i32,
             sample_rate: audio_format.sample_rate
@@ -132,6 +157,7 @@ impl AudioProcessor {
         }
 
         let _ = unsafe { self.audio_processor.set_processing(if enable { 1 } else { 0 }) };
+        context.processing = enable;
 
         Ok(())
     }
@@ -153,13 +179,19 @@ impl AudioProcessor {
     }
     */
 
-    pub fn get_bus_input_arrangement(&self) -> u64 {
+    /// Speaker arrangement of a bus, one bit per channel. Zero if the
+    /// processor does not report one.
+    pub fn get_bus_arrangement(&self, direction: i32, index: i32) -> u64 {
         let mut bus_arrangement: u64 = 0;
         unsafe {
-            if self.audio_processor.get_bus_arrangement(BusDirections::kInput as i32, 0, &mut bus_arrangement) == kResultOk { bus_arrangement } else { 0 }
+            if self.audio_processor.get_bus_arrangement(direction, index, &mut bus_arrangement) == kResultOk { bus_arrangement } else { 0 }
         }
     }
 
+    pub fn get_bus_input_arrangement(&self) -> u64 {
+        self.get_bus_arrangement(BusDirections::kInput as i32, 0)
+    }
+
     pub fn set_bus_input_arrangements(&self, bus_arrangement: u64) -> bool {
         let mut value = bus_arrangement;
         unsafe {
@@ -168,10 +200,7 @@ impl AudioProcessor {
     }
 
     pub fn get_bus_output_arrangement(&self) -> u64 {
-        let mut bus_arrangement: u64 = 0;
-        unsafe {
-            if self.audio_processor.get_bus_arrangement(BusDirections::kOutput as i32, 0, &mut bus_arrangement) == kResultOk { bus_arrangement } else { 0 }
-        }
+        self.get_bus_arrangement(BusDirections::kOutput as i32, 0)
     }
 
     pub fn set_bus_output_arrangements(&self, bus_arrangement: u64) -> bool {
@@ -197,4 +226,19 @@ impl AudioProcessor {
         unsafe { audio_processor.get_tail_samples() as usize }
     }
 
+    pub fn get_tail(&self) -> Tail {
+        let tail_samples = unsafe { self.audio_processor.get_tail_samples() };
+        match tail_samples {
+            K_NO_TAIL => Tail::None,
+            K_INFINITE_TAIL => Tail::Infinite,
+            _ => Tail::Samples(tail_samples as usize)
+        }
+    }
+
+    pub fn advance_process_context(&mut self, num_samples: usize) {
+        let process_context = self.context.process_context.as_mut();
+        process_context.project_time_samples += num_samples as i64;
+        process_context.continuous_time_samples += num_samples as i64;
+    }
+
 }
@@ -0,0 +1,116 @@
+//!
+//! VST3 bundle layout
+//!
+//! A VST3 plugin is either a single library file (legacy Windows layout)
+//! or a bundle folder containing one library per architecture:
+//!
+//! ```text
+//! Plugin.vst3/Contents/x86_64-win/Plugin.vst3
+//! Plugin.vst3/Contents/x86_64-linux/Plugin.so
+//! ```
+//!
+
+use std::path::{Path, PathBuf};
+
+use crate::utils::slashify_path;
+
+pub const BUNDLE_EXTENSION: &str = "vst3";
+
+/// Name of the architecture folder inside a bundle for this host.
+pub fn get_architecture_dir() -> &'static str {
+    if cfg!(all(windows, target_arch = "x86_64")) {
+        "x86_64-win"
+    } else if cfg!(all(windows, target_arch = "x86")) {
+        "x86-win"
+    } else if cfg!(all(windows, target_arch = "aarch64")) {
+        "arm64-win"
+    } else if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        "x86_64-linux"
+    } else if cfg!(all(target_os = "linux", target_arch = "x86")) {
+        "i386-linux"
+    } else if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+        "aarch64-linux"
+    } else if cfg!(all(target_os = "linux", target_arch = "arm")) {
+        "armv7l-linux"
+    } else {
+        "unknown"
+    }
+}
+
+/// File extension of the plugin library inside a bundle for this host.
+pub fn get_library_extension() -> &'static str {
+    if cfg!(windows) {
+        "vst3"
+    } else {
+        "so"
+    }
+}
+
+pub fn is_bundle_path(path: &Path) -> bool {
+    match path.extension() {
+        Some(ext) => ext.to_ascii_lowercase() == BUNDLE_EXTENSION,
+        None => false
+    }
+}
+
+/// Resolves the library to load from a `.vst3` path. Single-file plugins
+/// resolve to themselves, bundle folders to the library matching the host
+/// architecture. Returns None if the bundle has no matching library.
+pub fn resolve_library_path(bundle_path: &Path) -> Option<PathBuf> {
+
+    if bundle_path.is_file() {
+        if cfg!(windows) {
+            return Some(bundle_path.to_path_buf());
+        }
+        return None;
+    }
+
+    if !bundle_path.is_dir() {
+        return None;
+    }
+
+    let name = bundle_path.file_stem()?;
+
+    let mut library_path = bundle_path.join("Contents").join(get_architecture_dir()).join(name);
+    library_path.set_extension(get_library_extension());
+
+    if library_path.is_file() {
+        Some(library_path)
+    } else {
+        None
+    }
+}
+
+/// Returns the bundle folder for a library resolved by `resolve_library_path`.
+pub fn get_bundle_path(library_path: &str) -> String {
+    let path = Path::new(library_path);
+
+    match path.parent().and_then(|p| p.parent()) {
+        Some(contents_path) if contents_path.file_name().map(|n| n == "Contents").unwrap_or(false) => {
+            match contents_path.parent() {
+                Some(bundle_path) => {
+                    return slashify_path(&bundle_path.to_string_lossy());
+                },
+                None => {}
+            };
+        },
+        _ => {}
+    };
+
+    slashify_path(library_path)
+}
+
+/// Expands a leading `~` to the user home directory.
+pub fn expand_home_dir(path: &str) -> String {
+    if path == "~" || path.starts_with("~/") {
+        let home = if cfg!(windows) { std::env::var("USERPROFILE") } else { std::env::var("HOME") };
+        match home {
+            Ok(home) => {
+                return slashify_path(&format!("{}{}", home, &path[1..]));
+            },
+            Err(_) => {}
+        };
+    }
+
+    path.to_string()
+}
@@ -5,12 +5,25 @@
 // registry settings
 pub const REGISTRY_CACHE_DISABLE: bool = false;
 pub const REGISTRY_CACHE_FILENAME: &str = ".plugin_cache.toml";
+pub const REGISTRY_SCAN_FOLLOW_SYMLINKS: bool = true;
+pub const REGISTRY_SCAN_MAX_DEPTH: usize = 16;
+pub const REGISTRY_SCAN_OUT_OF_PROCESS: bool = true; // probe plugins in a child process
+pub const REGISTRY_SCAN_TIMEOUT_SECONDS: u64 = 30;
+pub const REGISTRY_SCAN_USE_MODULE_INFO: bool = true; // read moduleinfo.json instead of loading the plugin
+#[cfg(windows)]
 pub const VST_DEFAULT_DIR: &str = "C:/Program Files/Common Files/VST3";
+#[cfg(windows)]
 pub const VST_DIRS: &[&str] = &[
     "D:/Work/vsthacks/megasynth/build/VST3/Debug/megasynth.vst3/Contents/x86_64-win",
     "C:/Tools/sdk/VST_SDK/vst3sdk/build/VST3/Debug/host-checker.vst3/Contents/x86_64-win",
     VST_DEFAULT_DIR
 ];
+#[cfg(not(windows))]
+pub const VST_DIRS: &[&str] = &[
+    "~/.vst3",
+    "/usr/lib/vst3",
+    "/usr/local/lib/vst3"
+];
 
 // choosing the ASIO device
 pub const DEVICE_ASIO4ALL: &str = "ASIO4ALL v2";
@@ -18,19 +31,44 @@ pub const DEVICE_YAMAHA: &str = "Yamaha Steinberg USB ASIO";
 pub const DEVICE_UMC: &str = "UMC ASIO Driver";
 pub const DEVICE_REALTEK: &str = "Realtek ASIO";
 
+// choosing the audio backend ("asio" or "null")
+pub const AUDIO_BACKEND: &str = "asio";
+
 // general ASIO output
 pub const ASIO_DEVICE_NAME: &str = DEVICE_ASIO4ALL;
 pub const ASIO_BUFFER_SIZE: usize = 0; // 0 to use default
 pub const ASIO_SAMPLE_RATE: f64 = 44100.0;
 
+// live MIDI input timing ("immediate" or "block")
+pub const MIDI_INPUT_TIMING: &str = "block";
+
+// null (headless) output
+pub const NULL_DEVICE_NAME: &str = "null";
+pub const NULL_BUFFER_SIZE: usize = 256;
+pub const NULL_SAMPLE_RATE: f64 = 48000.0;
+
+// offline rendering
+pub const RENDER_SAMPLE_RATE: f64 = 48000.0;
+pub const RENDER_BLOCK_SIZE: usize = 512;
+pub const RENDER_MAX_TAIL_SECONDS: f64 = 10.0; // limit for long or infinite tails
+
+// stress test
+pub const STRESS_THREADS: usize = 4;
+pub const STRESS_RUN_TIME_SECONDS: u64 = 10;
+pub const STRESS_EVENTS_PER_SECOND: u32 = 2000; // per thread
+pub const STRESS_DRAIN_TIME_MS: u64 = 500; // audio keeps running after the producers stopped
+
+// headless operation
+pub const HEADLESS_RUN_TIME_SECONDS: u64 = 10; // run time when no window is open
+pub const IDLE_INTERVAL_MS: u64 = 20; // polling interval for processor outputs
+
 // choosing the VST plugin
 pub const FM8_CLASS_ID: &str = "4E545356666966386D38000000000000"; // FM8
 pub const MEGASYNTH_CLASS_ID: &str = "3C2A31A836CE1F5088C9096932F9A0EA"; // MEGASYNTH
 pub const ANALOG_LAB: &str = "7574724156415349416C617650726F63"; // Analog Lab
 pub const HOST_CHECKER: &str = "0E19FC23DD029944A8D2230E50617DA3"; // Host Checker
 pub const OTHER_CLASS_ID: &str = "4E5453564B696B386F6E74616B742038"; // some thing...
-pub const VST_CLSID: &str = FM8_CLASS_ID;
+pub const VST_CLASS: &str = "FM8"; // class name or class id
 
 // debugging settings
-pub const ENABLE_COMPONENT_HANDLER: bool = false;
 pub const ENABLE_VIEW_RESIZE: bool = true;
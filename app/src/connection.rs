@@ -0,0 +1,188 @@
+//!
+//! Connection between component and edit controller
+//!
+//! Plugins with a separate controller exchange messages over
+//! `IConnectionPoint`. The two halves are not connected directly, each
+//! side talks to a host proxy that forwards messages to the other side.
+//! Messages are delivered on the UI thread only, notifications from other
+//! threads are queued and dispatched by the idle loop.
+//!
+
+use std::{ptr::null_mut, sync::Mutex, thread::{self, ThreadId}};
+
+use log::{*};
+use vst3_com::VstPtr;
+use vst3_sys::{base::{kInvalidArgument, kResultFalse, kResultOk, tresult, IUnknown}, utils::SharedVstPtr, vst::{IConnectionPoint, IConnectionPointVTable, IEditController, IMessage}, VST3};
+
+use crate::{error::Error, host::Host};
+
+pub fn register_interfaces(host: &Host) {
+    host.register_interface_support::<dyn IConnectionPoint>();
+}
+
+#[VST3(implements(IConnectionPoint))]
+pub struct ConnectionProxy {
+    destination: Mutex<Option<VstPtr<dyn IConnectionPoint>>>,
+    pending_messages: Mutex<Vec<VstPtr<dyn IMessage>>>,
+    ui_thread: ThreadId
+}
+
+impl ConnectionProxy {
+    pub fn new(destination: &VstPtr<dyn IConnectionPoint>) -> Box<Self> {
+        let instance = Self::allocate(Mutex::new(Some(destination.clone())), Mutex::new(Vec::new()), thread::current().id());
+        instance
+    }
+
+    pub fn get_shared_ptr(&mut self) -> SharedVstPtr<dyn IConnectionPoint> {
+        let shared_vst_ptr: SharedVstPtr<dyn IConnectionPoint> = unsafe {
+            std::mem::transmute(self as * mut _)
+        };
+        shared_vst_ptr
+    }
+
+    pub fn get_null_ptr() -> SharedVstPtr<dyn IConnectionPoint> {
+        let null_ptr: *mut IConnectionPointVTable = null_mut();
+        let ptr: SharedVstPtr<dyn IConnectionPoint> = unsafe {
+            std::mem::transmute(null_ptr as *mut _)
+        };
+        return ptr
+    }
+
+    /// Forwards the messages queued from other threads. UI thread only.
+    pub fn dispatch_pending(&self) {
+        let messages = std::mem::take(&mut *self.pending_messages.lock().unwrap());
+        for message in messages {
+            self.forward(&message);
+        }
+    }
+
+    pub fn close(&self) {
+        self.destination.lock().unwrap().take();
+        self.pending_messages.lock().unwrap().clear();
+    }
+
+    fn forward(&self, message: &VstPtr<dyn IMessage>) -> tresult {
+        let destination = match self.destination.lock().unwrap().as_ref() {
+            Some(destination) => destination.clone(),
+            None => {
+                return kResultFalse;
+            }
+        };
+
+        let message_ptr: SharedVstPtr<dyn IMessage> = unsafe { std::mem::transmute(message.as_ptr()) };
+        unsafe { destination.notify(message_ptr) }
+    }
+}
+
+impl IConnectionPoint for ConnectionProxy {
+    unsafe fn connect(&self, _other: SharedVstPtr<dyn IConnectionPoint>) -> tresult {
+        // the host connects the proxies, plugins must not reconnect them
+        kResultFalse
+    }
+
+    unsafe fn disconnect(&self, _other: SharedVstPtr<dyn IConnectionPoint>) -> tresult {
+        self.close();
+        kResultOk
+    }
+
+    unsafe fn notify(&self, message: SharedVstPtr<dyn IMessage>) -> tresult {
+        let message = match message.upgrade() {
+            Some(message) => message,
+            None => {
+                return kInvalidArgument;
+            }
+        };
+
+        if thread::current().id() == self.ui_thread {
+            return self.forward(&message);
+        }
+
+        trace!("connection proxy: queue message from non ui thread");
+        self.pending_messages.lock().unwrap().push(message);
+
+        kResultOk
+    }
+}
+
+/// Connected component and controller, disconnects both sides on drop.
+pub struct Connection {
+    component: VstPtr<dyn IConnectionPoint>,
+    controller: VstPtr<dyn IConnectionPoint>,
+    component_proxy: Box<ConnectionProxy>, // given to the component, forwards to the controller
+    controller_proxy: Box<ConnectionProxy>, // given to the controller, forwards to the component
+    connected: bool
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+impl Connection {
+    /// Connects both halves, returns `None` if either side is not a connection point.
+    pub fn connect(component: &VstPtr<dyn IUnknown>, controller: &VstPtr<dyn IEditController>) -> Result<Option<Self>, Error> {
+        trace!("connect component and controller");
+
+        let component = match component.cast::<dyn IConnectionPoint>() {
+            Some(intf) => intf,
+            None => {
+                trace!("component does not provide connection point interface");
+                return Ok(None);
+            }
+        };
+
+        let controller = match controller.cast::<dyn IConnectionPoint>() {
+            Some(intf) => intf,
+            None => {
+                trace!("controller does not provide connection point interface");
+                return Ok(None);
+            }
+        };
+
+        let mut connection = Self {
+            component_proxy: ConnectionProxy::new(&controller),
+            controller_proxy: ConnectionProxy::new(&component),
+            component,
+            controller,
+            connected: false
+        };
+
+        let result = unsafe { connection.component.connect(connection.component_proxy.get_shared_ptr()) };
+        if result != kResultOk {
+            return Err(Error::from("failed to connect component"));
+        }
+
+        let result = unsafe { connection.controller.connect(connection.controller_proxy.get_shared_ptr()) };
+        if result != kResultOk {
+            unsafe { connection.component.disconnect(connection.component_proxy.get_shared_ptr()) };
+            return Err(Error::from("failed to connect controller"));
+        }
+
+        connection.connected = true;
+
+        Ok(Some(connection))
+    }
+
+    pub fn dispatch_pending(&self) {
+        self.component_proxy.dispatch_pending();
+        self.controller_proxy.dispatch_pending();
+    }
+
+    pub fn disconnect(&mut self) {
+        if !self.connected {
+            return;
+        }
+
+        trace!("disconnect component and controller");
+
+        unsafe {
+            self.component.disconnect(self.component_proxy.get_shared_ptr());
+            self.controller.disconnect(self.controller_proxy.get_shared_ptr());
+        }
+
+        self.component_proxy.close();
+        self.controller_proxy.close();
+        self.connected = false;
+    }
+}
@@ -1,21 +1,41 @@
 
-use std::ptr::null_mut;
+use std::{cell::RefCell, collections::HashMap, ptr::null_mut, sync::{atomic::{AtomicI32, Ordering}, Arc}};
 
 use vst3_sys::{gui::{IPlugView, IPlugViewVTable}, utils::SharedVstPtr, vst::{IComponentHandler, ParameterInfo, String128}, VST3};
 use vst3_com::*;
-use vst3_sys::{base::*, vst::IEditController};
+use vst3_sys::{base::*, vst::{IEditController, IMidiMapping}};
 
-use crate::{config::ENABLE_COMPONENT_HANDLER, error::Error, host::Host, instance::Instance, stream::ByteStream, view::View};
+use crate::{connection::Connection, error::Error, host::Host, instance::Instance, parameters::{ParamID, ParameterChange}, spsc::SpscQueue, stream::ByteStream, view::View};
 
 use log::{*};
 
+/// Completed edit gesture (begin_edit .. end_edit) of a parameter.
+#[derive(Clone, Copy, Debug)]
+pub struct ParameterEdit {
+    pub id: ParamID,
+    pub begin_value: f64,
+    pub end_value: f64
+}
+
+#[derive(Default)]
+struct EditGestures {
+    active: HashMap<ParamID, f64>, // begin value of edits in progress
+    completed: Vec<ParameterEdit>
+}
+
+/// Receives edits from the plugin GUI. Must only be called on the UI
+/// thread, which is also the producer of the instrument parameter queue.
 #[VST3(implements(IComponentHandler))]
 pub struct ComponentHandler {
+    controller: RefCell<Option<VstPtr<dyn IEditController>>>,
+    parameter_queue: Arc<SpscQueue<ParameterChange>>,
+    gestures: RefCell<EditGestures>,
+    restart_flags: AtomicI32
 }
 
 impl ComponentHandler {
-    pub fn new() -> Box<Self> {
-        let instance = Self::allocate();
+    pub fn new(parameter_queue: Arc<SpscQueue<ParameterChange>>) -> Box<Self> {
+        let instance = Self::allocate(RefCell::new(None), parameter_queue, RefCell::new(EditGestures::default()), AtomicI32::new(0));
         instance
     }
 
@@ -33,34 +53,98 @@ impl ComponentHandler {
         };
         return ptr
     }
+
+    pub fn attach(&self, controller: &VstPtr<dyn IEditController>) {
+        self.controller.replace(Some(controller.clone()));
+    }
+
+    pub fn detach(&self) {
+        self.controller.replace(None);
+    }
+
+    pub fn is_editing(&self, id: ParamID) -> bool {
+        self.gestures.borrow().active.contains_key(&id)
+    }
+
+    /// Returns the gestures finished since the last call, oldest first.
+    pub fn take_completed_edits(&self) -> Vec<ParameterEdit> {
+        std::mem::take(&mut self.gestures.borrow_mut().completed)
+    }
+
+    /// Returns the restart flags requested since the last call.
+    pub fn take_restart_flags(&self) -> i32 {
+        self.restart_flags.swap(0, Ordering::AcqRel)
+    }
+
+    fn get_param_normalized(&self, id: ParamID) -> f64 {
+        match self.controller.borrow().as_ref() {
+            Some(controller) => unsafe { controller.get_param_normalized(id) },
+            None => 0.0
+        }
+    }
 }
 
 impl IComponentHandler for ComponentHandler {
     unsafe fn begin_edit(&self, id: vst3_sys::vst::ParamID) -> tresult {
         trace!("component handler: begin edit {}", id);
-        kResultFalse
+
+        let begin_value = self.get_param_normalized(id);
+        self.gestures.borrow_mut().active.insert(id, begin_value);
+
+        kResultOk
     }
 
     unsafe fn end_edit(&self, id: vst3_sys::vst::ParamID) -> tresult {
         trace!("component handler: end edit {}", id);
-        kResultFalse
+
+        let begin_value = match self.gestures.borrow_mut().active.remove(&id) {
+            Some(begin_value) => begin_value,
+            None => {
+                return kResultFalse;
+            }
+        };
+
+        let edit = ParameterEdit {
+            id,
+            begin_value,
+            end_value: self.get_param_normalized(id)
+        };
+
+        self.gestures.borrow_mut().completed.push(edit);
+
+        kResultOk
     }
 
     unsafe fn perform_edit(&self, id: vst3_sys::vst::ParamID, value_normalized: vst3_sys::vst::ParamValue) -> tresult {
         trace!("component handler: perform edit {}, value normalized: {}", id, value_normalized);
-        kResultFalse
+
+        let change = ParameterChange {
+            id,
+            sample_offset: 0,
+            value: value_normalized
+        };
+
+        match self.parameter_queue.push(change) {
+            Ok(_) => kResultOk,
+            Err(_) => kResultFalse
+        }
     }
 
     unsafe fn restart_component(&self, flags: i32) -> tresult {
-        trace!("component handler: restart component {}", flags);
-        kResultFalse
+        trace!("component handler: restart component {:#x}", flags);
+
+        // handled by the host on the UI thread, see Instrument::take_restart_flags
+        self.restart_flags.fetch_or(flags, Ordering::AcqRel);
+
+        kResultOk
     }
 }
 
 pub struct EditController {
     pub controller: VstPtr<dyn IEditController>,
     pub component_handler: Box<ComponentHandler>,
-    pub is_instance: bool
+    pub is_instance: bool,
+    connection: Option<Connection>
 }
 
 impl Drop for EditController {
@@ -70,12 +154,12 @@ impl Drop for EditController {
 }
 
 impl EditController {
-    pub fn new(instance: &Instance) -> Result<Self, Error> {
+    pub fn new(instance: &Instance, parameter_queue: Arc<SpscQueue<ParameterChange>>) -> Result<Self, Error> {
         trace!("new");
 
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
 
-        let component_handler = ComponentHandler::allocate();
+        let component_handler = ComponentHandler::new(parameter_queue);
 
         let (controller, is_instance) = match instance.query_edit_controller_intf() {
             Ok(intf) => {
@@ -96,35 +180,69 @@ impl EditController {
 
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
 
+        component_handler.attach(&controller);
+
         Ok(Self {
             controller,
             component_handler,
-            is_instance
+            is_instance,
+            connection: None
         })
     }
 
     pub fn initialize(&mut self, host: &Host) -> Result<(), Error> {
 
-        if !self.is_instance {
-            return Ok(());
+        if self.is_instance {
+            let host_context = host.get_context()?;
+            let host_context_ptr = host_context.as_ptr();
+            let result = unsafe { self.controller.initialize(host_context_ptr as *mut c_void) };
+            if result != kResultOk {
+                return Err(Error::from("failed to initialize edit controller component"));
+            }
         }
 
-        let host_context = host.get_context()?;
-        let host_context_ptr = host_context.as_ptr();
-        let result = unsafe {
-            if ENABLE_COMPONENT_HANDLER {
-                self.controller.set_component_handler(self.component_handler.get_shared_ptr());
+        // the handler is set after initialize, also for single component plugins
+        let handler = self.component_handler.get_shared_ptr();
+        match self.set_component_handler(handler) {
+            Ok(_) => {},
+            Err(e) => {
+                warn!("{}", e.message());
             }
-
-            self.controller.initialize(host_context_ptr as *mut c_void)
         };
-        if result != kResultOk {
-            return Err(Error::from("failed to initialize edit controller component"));
+
+        Ok(())
+    }
+
+    /// Connects a separate controller with the component, call after both are initialized.
+    pub fn connect(&mut self, instance: &Instance) -> Result<(), Error> {
+        if !self.is_instance || self.connection.is_some() {
+            return Ok(());
         }
 
+        self.connection = Connection::connect(&instance.instance, &self.controller)?;
+
         Ok(())
     }
 
+    pub fn disconnect(&mut self) {
+        match self.connection.take() {
+            Some(mut connection) => {
+                connection.disconnect();
+            },
+            None => {}
+        };
+    }
+
+    /// Forwards messages the plugin sent from other threads, call from the UI thread.
+    pub fn dispatch_messages(&self) {
+        match self.connection.as_ref() {
+            Some(connection) => {
+                connection.dispatch_pending();
+            },
+            None => {}
+        };
+    }
+
     pub fn terminate(&mut self) -> Result<(), Error> {
         if self.is_instance {
             unsafe {
@@ -136,6 +254,9 @@ impl EditController {
     }
 
     pub fn dispose(&mut self) {
+        self.disconnect();
+        let _ = self.set_component_handler(ComponentHandler::get_null_ptr());
+        self.component_handler.detach();
         let _ = self.terminate();
         self.is_instance = false;
     }
@@ -145,7 +266,7 @@ impl EditController {
         let state_intf = state.get_shared_ptr();
         let result = unsafe { self.controller.set_component_state(state_intf) };
         if result != kResultOk {
-            return Err(Error::from("failed to get edit controller state"));
+            return Err(Error::from("failed to set edit controller component state"));
         }
         Ok(())
     }
@@ -156,7 +277,7 @@ impl EditController {
         let state_intf = state.get_shared_ptr();
         let result = unsafe { self.controller.set_state(state_intf) };
         if result != kResultOk {
-            return Err(Error::from("failed to get edit controller state"));
+            return Err(Error::from("failed to set edit controller state"));
         }
         Ok(())
     }
@@ -288,6 +409,13 @@ impl EditController {
         Ok(())
     }
 
+    pub fn query_midi_mapping_intf(&self) -> Result<VstPtr<dyn IMidiMapping>, Error> {
+        match self.controller.cast::<dyn IMidiMapping>() {
+            Some(intf) => Ok(intf),
+            None => Err(Error::from("edit controller does not provide midi mapping interface"))
+        }
+    }
+
     pub fn create_view(&self) -> Result<View, Error> {
 
         trace!("create_view");
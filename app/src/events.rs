@@ -1,19 +1,410 @@
-use std::{ptr::null_mut, sync::Mutex};
-use vst3_sys::{base::{kResultFalse, kResultOk, tresult}, utils::StaticVstPtr, vst::{Event, EventData, EventTypes, IEventList, IEventListVTable, NoteOffEvent, NoteOnEvent}, VST3};
-use log::{*};
+use std::{cell::UnsafeCell, ptr::{null, null_mut}, sync::{atomic::{AtomicBool, Ordering}, Arc}};
+use vst3_sys::{base::{kResultFalse, kResultOk, tchar, tresult}, utils::StaticVstPtr, vst::{ChordEvent, DataEvent, Event, EventData, EventTypes, IEventList, IEventListVTable, LegacyMidiCCOutEvent, NoteExpressionTextEvent, NoteExpressionValueEvent, NoteOffEvent, NoteOnEvent, PolyPressureEvent, ScaleEvent}, VST3};
 
-use crate::error::Error;
+use crate::{error::Error, instrument::ProcessStats, midi::{MidiMessage, CTRL_AFTER_TOUCH, CTRL_PITCH_BEND, MIDI_CHANNEL_COUNT}, spsc::SpscQueue};
 
-const MAX_EVENT_COUNT: usize = 256;
+pub const MAX_EVENT_COUNT: usize = 256;
 
+pub const DATA_TYPE_MIDI_SYSEX: u32 = 0; // DataEvent::type_
+pub const NO_NOTE_ID: i32 = -1;
+
+const NOTE_COUNT: usize = 128;
+const TERMINATOR_SIZE: usize = std::mem::size_of::<tchar>(); // stored after every payload
+
+/// Event reported by the processor, `block_position` is the project time
+/// in samples of the block the event belongs to. Data, text, chord and scale
+/// events point into `payload`, a copy of the data the processor reported.
+pub struct OutputEvent {
+    pub block_position: i64,
+    pub event: Event,
+    pub payload: Vec<u8>
+}
+
+impl OutputEvent {
+    /// Takes an event off the output queue, copies its payload and releases
+    /// the payload buffer. Control thread only.
+    pub fn new(queued: &QueuedOutputEvent, payloads: &PayloadPool) -> Self {
+        let mut event = queued.event;
+
+        let payload = match EventList::get_payload(&event) {
+            Some((ptr, size)) => {
+                let payload = unsafe { std::slice::from_raw_parts(ptr, size) }.to_vec();
+                payloads.release(ptr);
+                EventList::set_payload(&mut event, payload.as_ptr(), payload.len());
+                payload
+            },
+            None => Vec::new()
+        };
+
+        Self {
+            block_position: queued.block_position,
+            event,
+            payload
+        }
+    }
+
+    /// The event as a MIDI message, for note, poly pressure and legacy MIDI
+    /// controller events.
+    pub fn to_midi(&self) -> Option<MidiMessage> {
+        let event = &self.event;
+        let message = unsafe {
+            match event.type_ {
+                t if t == EventTypes::kNoteOnEvent as u16 => {
+                    let note_on = event.event.note_on;
+                    MidiMessage::NoteOn { channel: (note_on.channel & 0x0F) as u8, pitch: (note_on.pitch & 0x7F) as u8, velocity: Self::to_midi_value(note_on.velocity) }
+                },
+                t if t == EventTypes::kNoteOffEvent as u16 => {
+                    let note_off = event.event.note_off;
+                    MidiMessage::NoteOff { channel: (note_off.channel & 0x0F) as u8, pitch: (note_off.pitch & 0x7F) as u8, velocity: Self::to_midi_value(note_off.velocity) }
+                },
+                t if t == EventTypes::kPolyPressureEvent as u16 => {
+                    let poly_pressure = event.event.poly_pressure;
+                    MidiMessage::PolyPressure { channel: (poly_pressure.channel & 0x0F) as u8, pitch: (poly_pressure.pitch & 0x7F) as u8, pressure: Self::to_midi_value(poly_pressure.pressure) }
+                },
+                t if t == EventTypes::kLegacyMIDICCOutEvent as u16 => {
+                    let cc = event.event.legacy_midi_cc_out;
+                    let channel = (cc.channel & 0x0F) as u8;
+                    let value = cc.value as u8 & 0x7F;
+                    match cc.control_number as i16 {
+                        0..=127 => MidiMessage::ControlChange { channel, controller: cc.control_number, value },
+                        CTRL_AFTER_TOUCH => MidiMessage::ChannelPressure { channel, pressure: value },
+                        CTRL_PITCH_BEND => MidiMessage::PitchBend { channel, value: value as u16 | ((cc.value2 as u16 & 0x7F) << 7) },
+                        _ => {
+                            return None;
+                        }
+                    }
+                },
+                _ => {
+                    return None;
+                }
+            }
+        };
+
+        Some(message)
+    }
+
+    fn to_midi_value(value: f32) -> u8 {
+        (value * 127.0).round().clamp(0.0, 127.0) as u8
+    }
+}
+
+/// Event on its way to the audio thread, its payload is in the input
+/// payload pool.
+#[derive(Clone, Copy)]
+pub struct QueuedEvent {
+    pub event: Event
+}
+
+/// Output event on its way from the audio thread, its payload is in the
+/// output payload pool.
+#[derive(Clone, Copy)]
+pub struct QueuedOutputEvent {
+    pub block_position: i64,
+    pub event: Event
+}
+
+// events are plain data, payloads are owned by the payload pool
+unsafe impl Send for QueuedEvent {}
+unsafe impl Send for QueuedOutputEvent {}
+
+/// Fixed size buffers for the payload of data, text, chord and scale
+/// events, which only carry a pointer. Any thread may store a payload, the
+/// thread that is done with the event releases it. Does not lock or allocate.
+pub struct PayloadPool {
+    slots: Vec<PayloadSlot>,
+    slot_size: usize // bytes
+}
+
+struct PayloadSlot {
+    buffer: Box<[UnsafeCell<u64>]>, // u64 keeps text payloads aligned
+    in_use: AtomicBool
+}
+
+unsafe impl Send for PayloadPool {}
+unsafe impl Sync for PayloadPool {}
+
+impl PayloadPool {
+    pub fn new(slot_count: usize, slot_size: usize) -> Self {
+        let words = slot_size.div_ceil(8);
+        let slots = (0..slot_count).map(|_| {
+            PayloadSlot {
+                buffer: (0..words).map(|_| UnsafeCell::new(0)).collect(),
+                in_use: AtomicBool::new(false)
+            }
+        }).collect();
+
+        Self {
+            slots,
+            slot_size: words * 8
+        }
+    }
+
+    /// Copies a payload into a free buffer and zero terminates it for text.
+    /// The returned pointer stays valid until it is released.
+    pub fn store(&self, payload: &[u8]) -> Result<*const u8, Error> {
+        if payload.len() + TERMINATOR_SIZE > self.slot_size {
+            return Err(Error::from("event payload too large"));
+        }
+
+        for slot in self.slots.iter() {
+            if slot.in_use.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                let buffer = slot.buffer.as_ptr() as *mut u8;
+                unsafe {
+                    std::ptr::copy_nonoverlapping(payload.as_ptr(), buffer, payload.len());
+                    std::ptr::write_bytes(buffer.add(payload.len()), 0, TERMINATOR_SIZE);
+                }
+                return Ok(buffer);
+            }
+        }
+
+        Err(Error::from("no free event payload buffer"))
+    }
+
+    /// Releases the buffer `ptr` points to, other pointers are ignored.
+    pub fn release(&self, ptr: *const u8) {
+        for slot in self.slots.iter() {
+            if slot.buffer.as_ptr() as *const u8 == ptr {
+                slot.in_use.store(false, Ordering::Release);
+                return;
+            }
+        }
+    }
+}
+
+/// Note IDs of the held notes, a note off gets the ID of its note on.
+pub struct NoteIds {
+    held: [i32; MIDI_CHANNEL_COUNT * NOTE_COUNT],
+    next_id: i32
+}
+
+impl NoteIds {
+    pub fn new() -> Self {
+        Self {
+            held: [NO_NOTE_ID; MIDI_CHANNEL_COUNT * NOTE_COUNT],
+            next_id: 0
+        }
+    }
+
+    /// Allocates the ID of a new note. Also returns the ID of a note still
+    /// held on the same key or `NO_NOTE_ID`, that note must be released
+    /// first or its voice hangs.
+    pub fn note_on(&mut self, channel: u8, pitch: u8) -> (i32, i32) {
+        let id = self.next_id;
+        self.next_id = if self.next_id == i32::MAX { 0 } else { self.next_id + 1 };
+        let index = Self::index(channel, pitch);
+        let held_id = self.held[index];
+        self.held[index] = id;
+        (id, held_id)
+    }
+
+    /// Releases the ID of a held note, `NO_NOTE_ID` if the key is not held.
+    pub fn note_off(&mut self, channel: u8, pitch: u8) -> i32 {
+        let index = Self::index(channel, pitch);
+        let id = self.held[index];
+        self.held[index] = NO_NOTE_ID;
+        id
+    }
+
+    pub fn get(&self, channel: u8, pitch: u8) -> i32 {
+        self.held[Self::index(channel, pitch)]
+    }
+
+    fn index(channel: u8, pitch: u8) -> usize {
+        (channel as usize % MIDI_CHANNEL_COUNT) * NOTE_COUNT + (pitch as usize % NOTE_COUNT)
+    }
+}
+
+/// Event list passed to `IAudioProcessor::process`. The storage is
+/// preallocated and not synchronized: a list belongs to the thread that owns
+/// the instrument context, which is the audio thread while processing.
 #[VST3(implements(IEventList))]
 pub struct EventList {
-    events: Mutex<Vec<Event>>
+    events: UnsafeCell<Vec<Event>>
+}
+
+/// Producer side of an event queue. Every thread that plays events needs
+/// its own sender, the audio thread drains all of them at the start of a block.
+pub struct EventSender {
+    queue: Arc<SpscQueue<QueuedEvent>>,
+    claimed: Arc<AtomicBool>,
+    payloads: Arc<PayloadPool>
+}
+
+impl EventSender {
+    pub fn new(queue: Arc<SpscQueue<QueuedEvent>>, claimed: Arc<AtomicBool>, payloads: Arc<PayloadPool>) -> Self {
+        Self {
+            queue,
+            claimed,
+            payloads
+        }
+    }
+
+    /// Queues an event for the next block, the sample offset is relative to
+    /// the start of that block and may reach into the block after it.
+    pub fn send(&self, event: Event) -> Result<(), Error> {
+        match self.queue.push(QueuedEvent { event }) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::from("event queue overflow"))
+        }
+    }
+
+    /// Queues a data, chord or scale event with a copy of its payload, the
+    /// size or text length of the event is set from the payload.
+    pub fn send_with_payload(&self, mut event: Event, payload: &[u8]) -> Result<(), Error> {
+        let ptr = self.payloads.store(payload)?;
+        EventList::set_payload(&mut event, ptr, payload.len());
+
+        match self.queue.push(QueuedEvent { event }) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.payloads.release(ptr);
+                Err(Error::from("event queue overflow"))
+            }
+        }
+    }
+
+    /// Queues a note expression text, chord or scale event with a copy of
+    /// its UTF-16 text.
+    pub fn send_with_text(&self, event: Event, text: &[u16]) -> Result<(), Error> {
+        let payload = unsafe { std::slice::from_raw_parts(text.as_ptr() as *const u8, std::mem::size_of_val(text)) };
+        self.send_with_payload(event, payload)
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.claimed.store(false, Ordering::Release);
+    }
+}
+
+/// Event queues of an instrument, one per sender. A sender owns its queue
+/// until it is dropped, the audio thread drains all of them.
+pub struct EventSlots {
+    slots: Vec<(Arc<SpscQueue<QueuedEvent>>, Arc<AtomicBool>)>, // queue and whether a sender owns it
+    payloads: Arc<PayloadPool>
+}
+
+impl EventSlots {
+    pub fn new(sender_count: usize, queue_size: usize, payloads: Arc<PayloadPool>) -> Self {
+        let slots = (0..sender_count).map(|_| {
+            (Arc::new(SpscQueue::new(queue_size)), Arc::new(AtomicBool::new(false)))
+        }).collect();
+
+        Self {
+            slots,
+            payloads
+        }
+    }
+
+    /// Returns a sender for the first free queue.
+    pub fn claim(&self) -> Result<EventSender, Error> {
+        for (queue, claimed) in self.slots.iter() {
+            if claimed.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return Ok(EventSender::new(queue.clone(), claimed.clone(), self.payloads.clone()));
+            }
+        }
+        Err(Error::from("no free event queue"))
+    }
+
+    /// Returns the consumer side of all queues, for the audio thread.
+    pub fn create_input_events(&self) -> InputEvents {
+        InputEvents::new(self.slots.iter().map(|(queue, _)| queue.clone()).collect(), self.payloads.clone())
+    }
+}
+
+/// Consumer side of the event queues, owned by the audio thread. Holds the
+/// input event lists of this block and the next one, which are swapped
+/// after each block.
+pub struct InputEvents {
+    lists: [Box<EventList>; 2],
+    current: usize,
+    queues: Vec<Arc<SpscQueue<QueuedEvent>>>,
+    payloads: Arc<PayloadPool>
+}
+
+// moves to the audio thread once, the lists are only used there
+unsafe impl Send for InputEvents {}
+
+impl InputEvents {
+    pub fn new(queues: Vec<Arc<SpscQueue<QueuedEvent>>>, payloads: Arc<PayloadPool>) -> Self {
+        Self {
+            lists: [EventList::new(), EventList::new()],
+            current: 0,
+            queues,
+            payloads
+        }
+    }
+
+    /// Moves the events queued by the senders into the input lists. Events
+    /// beyond the end of this block go into the list of the next block.
+    pub fn dequeue(&mut self, buffer_size: usize, stats: &ProcessStats) {
+        let buffer_size = buffer_size.max(1) as i32;
+        let current = self.current;
+
+        for queue in self.queues.iter() {
+            while let Some(QueuedEvent { mut event }) = queue.pop() {
+                let index = if event.sample_offset < buffer_size {
+                    event.sample_offset = event.sample_offset.max(0);
+                    current
+                } else {
+                    event.sample_offset = (event.sample_offset - buffer_size).min(buffer_size - 1);
+                    1 - current
+                };
+
+                Self::add_to_list(&mut self.lists[index], &self.payloads, stats, event);
+            }
+        }
+    }
+
+    /// Adds an event to the list of this block.
+    pub fn add_event(&mut self, event: Event, stats: &ProcessStats) {
+        Self::add_to_list(&mut self.lists[self.current], &self.payloads, stats, event);
+    }
+
+    /// The list of this block.
+    pub fn get_current(&mut self) -> &mut EventList {
+        &mut self.lists[self.current]
+    }
+
+    /// Releases the payloads of this block and moves on to the next one.
+    pub fn finish_block(&mut self) {
+        let payloads = &self.payloads;
+        let list = &mut self.lists[self.current];
+
+        list.for_each_event(|event| {
+            match EventList::get_payload(event) {
+                Some((ptr, _)) => {
+                    payloads.release(ptr);
+                },
+                None => {}
+            };
+        });
+        list.clear();
+
+        self.current = 1 - self.current;
+    }
+
+    fn add_to_list(list: &mut EventList, payloads: &PayloadPool, stats: &ProcessStats, event: Event) {
+        match list.push_event(event) {
+            Ok(_) => {
+                stats.events.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(event) => {
+                match EventList::get_payload(&event) {
+                    Some((ptr, _)) => {
+                        payloads.release(ptr);
+                    },
+                    None => {}
+                };
+                stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+    }
 }
 
 impl EventList {
     pub fn new() -> Box<Self> {
-        let events = Mutex::new(Vec::new());
+        let events = UnsafeCell::new(Vec::with_capacity(MAX_EVENT_COUNT));
         let instance = Self::allocate(events);
         instance
     }
@@ -34,72 +425,204 @@ impl EventList {
         return ptr
     }
 
-    pub fn push_event(&mut self, event: Event) -> Result<(), Error> {
-        trace!("push event");
-
-        match self.events.lock() {
-            Ok(mut e) => {
+    fn events(&self) -> &Vec<Event> {
+        unsafe { &*self.events.get() }
+    }
 
-                if e.len() >= MAX_EVENT_COUNT {
-                    return Err(Error::from("event list overflow"));
-                }
+    /// Inserts an event keeping the list sorted by sample offset, events at
+    /// the same offset stay in the order they were pushed. An event that
+    /// does not fit is handed back.
+    pub fn push_event(&mut self, event: Event) -> Result<(), Event> {
+        let events = self.events.get_mut();
 
-                e.push(event);
-            },
-            Err(_) => {
-                return Err(Error::from("failed to lock event list"));
-            }
+        if events.len() >= MAX_EVENT_COUNT {
+            return Err(event);
         }
 
+        let index = events.partition_point(|e| e.sample_offset <= event.sample_offset);
+        events.insert(index, event);
+
         Ok(())
     }
 
-    pub fn clear(&mut self)  -> Result<(), Error> {
+    pub fn clear(&mut self) {
+        self.events.get_mut().clear();
+    }
 
-        trace!("clear all events");
+    pub fn len(&self) -> usize {
+        self.events().len()
+    }
 
-        match self.events.lock() {
-            Ok(mut e) => {
-                e.clear()
-            },
-            Err(_) => {
-                return Err(Error::from("failed to clear event list"));
+    /// Calls `f` for every event in the list, in list order.
+    pub fn for_each_event<F: FnMut(&Event)>(&self, mut f: F) {
+        for event in self.events().iter() {
+            f(event);
+        }
+    }
+
+    /// Pointer and size in bytes of the payload of data, text, chord and
+    /// scale events.
+    pub fn get_payload(event: &Event) -> Option<(*const u8, usize)> {
+        let tchar_size = std::mem::size_of::<tchar>();
+        let (ptr, size) = unsafe {
+            match event.type_ {
+                t if t == EventTypes::kDataEvent as u16 => (event.event.data.bytes, event.event.data.size as usize),
+                t if t == EventTypes::kNoteExpressionTextEvent as u16 => (event.event.note_expression_text.text as *const u8, event.event.note_expression_text.text_len as usize * tchar_size),
+                t if t == EventTypes::kChordEvent as u16 => (event.event.chord.text as *const u8, event.event.chord.text_len as usize * tchar_size),
+                t if t == EventTypes::kScaleEvent as u16 => (event.event.scale.text as *const u8, event.event.scale.text_len as usize * tchar_size),
+                _ => {
+                    return None;
+                }
             }
+        };
+
+        if ptr.is_null() {
+            return None;
         }
 
-        Ok(())
+        Some((ptr, size))
+    }
 
+    /// Points an event at its payload, text lengths are in characters
+    /// without the terminator.
+    pub fn set_payload(event: &mut Event, ptr: *const u8, size: usize) {
+        let text_len = size / std::mem::size_of::<tchar>();
+        match event.type_ {
+            t if t == EventTypes::kDataEvent as u16 => {
+                event.event.data.bytes = ptr;
+                event.event.data.size = size as u32;
+            },
+            t if t == EventTypes::kNoteExpressionTextEvent as u16 => {
+                event.event.note_expression_text.text = ptr as *const tchar;
+                event.event.note_expression_text.text_len = text_len as u32;
+            },
+            t if t == EventTypes::kChordEvent as u16 => {
+                event.event.chord.text = ptr as *const i16;
+                event.event.chord.text_len = text_len as u16;
+            },
+            t if t == EventTypes::kScaleEvent as u16 => {
+                event.event.scale.text = ptr as *const i16;
+                event.event.scale.text_len = text_len as u16;
+            },
+            _ => {}
+        }
     }
 
-    pub fn new_note_on_event(pitch: i16, velocity: f32) -> EventData {
+    /// Note on, `length` in samples or 0 if unknown, `tuning` in cents.
+    pub fn new_note_on_event(channel: i16, pitch: i16, tuning: f32, velocity: f32, length: i32, note_id: i32) -> EventData {
         EventData {
             note_on: NoteOnEvent {
-                channel: 0,
+                channel,
                 pitch,
-                tuning: 0.0,
+                tuning,
                 velocity,
-                length: 0,
-                note_id: -1
+                length,
+                note_id
             }
         }
     }
 
-    pub fn new_note_off_event(pitch: i16) -> EventData {
+    pub fn new_note_off_event(channel: i16, pitch: i16, tuning: f32, velocity: f32, note_id: i32) -> EventData {
         EventData {
             note_off: NoteOffEvent {
-                channel: 0,
+                channel,
+                pitch,
+                velocity,
+                note_id,
+                tuning
+            }
+        }
+    }
+
+    pub fn new_poly_pressure_event(channel: i16, pitch: i16, pressure: f32, note_id: i32) -> EventData {
+        EventData {
+            poly_pressure: PolyPressureEvent {
+                channel,
                 pitch,
-                velocity: 0.0,
-                note_id: -1,
-                tuning: 0.0
+                pressure,
+                note_id
             }
         }
     }
 
-    pub fn new_event(event_data: EventData, event_type: EventTypes) -> Event {
+    pub fn new_note_expression_value_event(type_id: u32, note_id: i32, value: f64) -> EventData {
+        EventData {
+            note_expression_value: NoteExpressionValueEvent {
+                type_id,
+                note_id,
+                value
+            }
+        }
+    }
+
+    /// Note expression text, send with `EventSender::send_with_text`.
+    pub fn new_note_expression_text_event(type_id: u32, note_id: i32) -> EventData {
+        EventData {
+            note_expression_text: NoteExpressionTextEvent {
+                type_id,
+                note_id,
+                text_len: 0,
+                text: null()
+            }
+        }
+    }
+
+    /// Chord, `mask` has bit 0 for the root and bit 11 for the major seventh.
+    /// The name is optional, send with `EventSender::send_with_text`.
+    pub fn new_chord_event(root: i16, bass_note: i16, mask: i16) -> EventData {
+        EventData {
+            chord: ChordEvent {
+                root,
+                bass_note,
+                mask,
+                text_len: 0,
+                text: null()
+            }
+        }
+    }
+
+    /// Scale, `mask` as for chords. The name is optional, send with
+    /// `EventSender::send_with_text`.
+    pub fn new_scale_event(root: i16, mask: i16) -> EventData {
+        EventData {
+            scale: ScaleEvent {
+                root,
+                mask,
+                text_len: 0,
+                text: null()
+            }
+        }
+    }
+
+    /// System exclusive message including F0 and F7, send with
+    /// `EventSender::send_with_payload`.
+    pub fn new_sysex_event() -> EventData {
+        EventData {
+            data: DataEvent {
+                size: 0,
+                type_: DATA_TYPE_MIDI_SYSEX,
+                bytes: null()
+            }
+        }
+    }
+
+    /// MIDI controller, `CTRL_AFTER_TOUCH` or `CTRL_PITCH_BEND` with the
+    /// LSB in `value` and the MSB in `value2`.
+    pub fn new_legacy_midi_cc_out_event(channel: i8, control_number: u8, value: i8, value2: i8) -> EventData {
+        EventData {
+            legacy_midi_cc_out: LegacyMidiCCOutEvent {
+                control_number,
+                channel,
+                value,
+                value2
+            }
+        }
+    }
+
+    pub fn new_event(event_data: EventData, event_type: EventTypes, sample_offset: i32) -> Event {
         Event {
             bus_index: 0,
-            sample_offset: 0,
+            sample_offset,
             ppq_position: 0.0,
             flags: 0x0,
             type_: event_type as u16,
@@ -107,44 +630,116 @@ impl EventList {
         }
     }
 
+    /// Calls `f` with the events for a note or poly pressure message and
+    /// returns false for other messages, controllers map to parameters
+    /// instead. Note IDs are allocated from `note_ids`, a note on for a key
+    /// that is still held releases the held note first.
+    pub fn new_events_from_midi<F: FnMut(Event)>(message: &MidiMessage, sample_offset: i32, note_ids: &mut NoteIds, mut f: F) -> bool {
+        match *message {
+            MidiMessage::NoteOn { channel, pitch, velocity } => {
+                let (note_id, held_id) = note_ids.note_on(channel, pitch);
+                if held_id != NO_NOTE_ID {
+                    let event_data = Self::new_note_off_event(channel as i16, pitch as i16, 0.0, 0.0, held_id);
+                    f(Self::new_event(event_data, EventTypes::kNoteOffEvent, sample_offset));
+                }
+                let event_data = Self::new_note_on_event(channel as i16, pitch as i16, 0.0, velocity as f32 / 127.0, 0, note_id);
+                f(Self::new_event(event_data, EventTypes::kNoteOnEvent, sample_offset));
+            },
+            MidiMessage::NoteOff { channel, pitch, velocity } => {
+                let note_id = note_ids.note_off(channel, pitch);
+                let event_data = Self::new_note_off_event(channel as i16, pitch as i16, 0.0, velocity as f32 / 127.0, note_id);
+                f(Self::new_event(event_data, EventTypes::kNoteOffEvent, sample_offset));
+            },
+            MidiMessage::PolyPressure { channel, pitch, pressure } => {
+                let event_data = Self::new_poly_pressure_event(channel as i16, pitch as i16, pressure as f32 / 127.0, note_ids.get(channel, pitch));
+                f(Self::new_event(event_data, EventTypes::kPolyPressureEvent, sample_offset));
+            },
+            _ => {
+                return false;
+            }
+        };
+        true
+    }
+
 }
 
 impl IEventList for EventList {
     unsafe fn get_event_count(&self) -> i32 {
-        //trace!("get event count");
-
-        match self.events.lock() {
-            Ok(e) => e.len() as i32,
-            Err(_) => 0
-        }
+        self.events().len() as i32
     }
 
     unsafe fn get_event(&self, index: i32, event_buffer_ptr: *mut Event) -> tresult {
-        trace!("get event");
-
         if event_buffer_ptr.is_null() {
             return kResultFalse;
         }
 
-        match self.events.lock() {
-            Ok(e) => {
-                if index < 0 || index as usize >= e.len() {
-                    return kResultFalse;
-                }
+        let events = self.events();
+        if index < 0 || index as usize >= events.len() {
+            return kResultFalse;
+        }
 
-                event_buffer_ptr.copy_from(e.as_ptr(), 1);
-            },
-            Err(_) => {
-                return kResultFalse;
-            }
+        event_buffer_ptr.copy_from(events.as_ptr().add(index as usize), 1);
+
+        kResultOk
+    }
+
+    unsafe fn add_event(&self, event_buffer_ptr: *mut Event) -> tresult {
+        if event_buffer_ptr.is_null() {
+            return kResultFalse;
         }
 
+        // the plugin only calls back from within process, never concurrently
+        let events = self.events.get();
+        if (*events).len() >= MAX_EVENT_COUNT {
+            return kResultFalse;
+        }
+
+        (*events).push(*event_buffer_ptr);
+
         kResultOk
     }
 
-    unsafe fn add_event(&self, _event_buffer_ptr: *mut Event) -> tresult {
-        trace!("add event");
-        return kResultFalse;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events_from_midi(message: MidiMessage, note_ids: &mut NoteIds) -> Vec<(u16, i32)> {
+        let mut events = Vec::new();
+        EventList::new_events_from_midi(&message, 0, note_ids, |event| {
+            let note_id = unsafe {
+                match event.type_ {
+                    t if t == EventTypes::kNoteOnEvent as u16 => event.event.note_on.note_id,
+                    t if t == EventTypes::kNoteOffEvent as u16 => event.event.note_off.note_id,
+                    _ => event.event.poly_pressure.note_id
+                }
+            };
+            events.push((event.type_, note_id));
+        });
+        events
     }
 
+    #[test]
+    fn retrigger_releases_held_note() {
+        let mut note_ids = NoteIds::new();
+        let note_on = EventTypes::kNoteOnEvent as u16;
+        let note_off = EventTypes::kNoteOffEvent as u16;
+
+        assert_eq!(events_from_midi(MidiMessage::NoteOn { channel: 0, pitch: 60, velocity: 100 }, &mut note_ids), vec![(note_on, 0)]);
+        assert_eq!(events_from_midi(MidiMessage::NoteOn { channel: 0, pitch: 60, velocity: 100 }, &mut note_ids), vec![(note_off, 0), (note_on, 1)]);
+        assert_eq!(events_from_midi(MidiMessage::NoteOn { channel: 1, pitch: 60, velocity: 100 }, &mut note_ids), vec![(note_on, 2)]);
+
+        assert_eq!(events_from_midi(MidiMessage::NoteOff { channel: 0, pitch: 60, velocity: 0 }, &mut note_ids), vec![(note_off, 1)]);
+        assert_eq!(events_from_midi(MidiMessage::NoteOff { channel: 0, pitch: 60, velocity: 0 }, &mut note_ids), vec![(note_off, NO_NOTE_ID)]);
+        assert_eq!(note_ids.get(1, 60), 2);
+    }
+
+    #[test]
+    fn controllers_are_not_events() {
+        let mut note_ids = NoteIds::new();
+        let message = MidiMessage::ControlChange { channel: 0, controller: 1, value: 64 };
+
+        assert!(!EventList::new_events_from_midi(&message, 0, &mut note_ids, |_| panic!("unexpected event")));
+    }
 }
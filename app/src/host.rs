@@ -1,21 +1,34 @@
-use std::{ffi::c_void, ptr::null_mut};
+use std::{ffi::c_void, ptr::null_mut, sync::Mutex};
 
 use log::{*};
-use vst3_com::{interfaces::iunknown::IID_IUNKNOWN, VstPtr, IID, REFIID};
-use vst3_sys::{base::{kResultFalse, kResultOk, tresult, IUnknown}, utils::StaticVstPtr, vst::{IHostApplication, IHostApplicationVTable, IPlugInterfaceSupport}, VST3};
+use vst3_com::{interfaces::iunknown::IID_IUNKNOWN, ComInterface, VstPtr, IID, REFIID};
+use vst3_sys::{base::{kInvalidArgument, kResultFalse, kResultOk, kResultTrue, tresult, IBStream, ISizeableStream, IUnknown}, utils::StaticVstPtr, vst::{IAudioProcessor, IComponent, IComponentHandler, IEditController, IEventList, IHostApplication, IHostApplicationVTable, IParamValueQueue, IParameterChanges, IPlugInterfaceSupport, IProcessContextRequirements}, VST3};
 
-use crate::error::Error;
+use crate::{error::Error, utils::GuidStringify};
 
 #[VST3(implements(IHostApplication, IPlugInterfaceSupport))]
 pub struct HostApplication {
+    supported_interfaces: Mutex<Vec<IID>>
 }
 
 impl HostApplication {
     pub fn new() -> Box<Self> {
-        let instance = Self::allocate();
+        let instance = Self::allocate(Mutex::new(Vec::new()));
         instance
     }
 
+    /// Announces an interface to plugins asking `IPlugInterfaceSupport`.
+    pub fn register_interface_support(&self, iid: &IID) {
+        let mut supported_interfaces = self.supported_interfaces.lock().unwrap();
+        if !supported_interfaces.contains(iid) {
+            supported_interfaces.push(*iid);
+        }
+    }
+
+    pub fn is_interface_supported(&self, iid: &IID) -> bool {
+        self.supported_interfaces.lock().unwrap().contains(iid)
+    }
+
     pub fn get_static_ptr(&mut self) -> StaticVstPtr<dyn IHostApplication> {
         let static_vst_ptr: StaticVstPtr<dyn IHostApplication> = unsafe {
             std::mem::transmute(self as *mut _)
@@ -68,15 +81,28 @@ impl IHostApplication for HostApplication {
         kResultOk
     }
 
-    unsafe fn create_instance(&self, _cid: *const IID, _iid: *const IID, _obj: *mut *mut c_void) -> tresult {
-        kResultFalse
+    unsafe fn create_instance(&self, cid: *const IID, iid: *const IID, obj: *mut *mut c_void) -> tresult {
+        if cid.is_null() || iid.is_null() || obj.is_null() {
+            return kInvalidArgument;
+        }
+
+        let result = crate::message::create_instance(&*cid, &*iid, obj);
+        trace!("host application: create instance {} -> {}", (*cid).to_string(), result);
+        result
     }
 
 }
 
 impl IPlugInterfaceSupport for HostApplication {
-    unsafe fn is_pluginterface_supported(&self, _iid: REFIID) -> tresult {
-        kResultFalse
+    unsafe fn is_pluginterface_supported(&self, iid: REFIID) -> tresult {
+        if iid.is_null() {
+            return kInvalidArgument;
+        }
+
+        let supported = self.is_interface_supported(&*iid);
+        trace!("host application: interface {} supported: {}", (*iid).to_string(), supported);
+
+        if supported { kResultTrue } else { kResultFalse }
     }
 }
 
@@ -88,9 +114,39 @@ impl Host {
     pub fn new() -> Result<Self, Error> {
         trace!("new");
         let host_application = HostApplication::new();
-        Ok(Self {
+        let host = Self {
             host_application
-        })
+        };
+        host.register_default_interfaces();
+        Ok(host)
+    }
+
+    /// Announces an interface to plugins. Optional subsystems register the
+    /// interfaces they use or implement when they are set up, see the
+    /// `register_interfaces` functions of their modules.
+    pub fn register_interface_support<I: ComInterface + ?Sized>(&self) {
+        self.host_application.register_interface_support(&I::IID);
+    }
+
+    pub fn is_interface_supported<I: ComInterface + ?Sized>(&self) -> bool {
+        self.host_application.is_interface_supported(&I::IID)
+    }
+
+    /// Interfaces every loaded plugin goes through.
+    fn register_default_interfaces(&self) {
+        // plugin interfaces used by the host
+        self.register_interface_support::<dyn IComponent>();
+        self.register_interface_support::<dyn IAudioProcessor>();
+        self.register_interface_support::<dyn IEditController>();
+        self.register_interface_support::<dyn IProcessContextRequirements>();
+
+        // host interfaces handed to plugins
+        self.register_interface_support::<dyn IComponentHandler>(); // edit_controller
+        self.register_interface_support::<dyn IBStream>(); // stream
+        self.register_interface_support::<dyn ISizeableStream>(); // stream
+        self.register_interface_support::<dyn IEventList>(); // events
+        self.register_interface_support::<dyn IParameterChanges>(); // parameters
+        self.register_interface_support::<dyn IParamValueQueue>(); // parameters
     }
 
     pub fn dispose(&mut self) {
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use vst3_com::{sys::GUID, *};
 use vst3_sys::{base::*, vst::{IAudioProcessor, IComponent, IEditController, IProcessContextRequirements, IoModes}};
 
-use crate::{error::Error, host::Host, plugin::Plugin};
+use crate::{error::Error, host::Host, plugin::{ClassInfo, Plugin}, stream::ByteStream, utils::GuidStringify};
 
 pub struct Instance {
     pub class_id: String,
@@ -53,6 +53,36 @@ impl Instance {
         self.plugin.clone()
     }
 
+    pub fn get_class_info(&self) -> Option<&ClassInfo> {
+        self.plugin.get_info().classes.iter().find(|class_info| class_info.cid.to_string() == self.class_id)
+    }
+
+    /// Version of the class as reported by the plugin factory, may be empty.
+    pub fn get_plugin_version(&self) -> String {
+        match self.get_class_info() {
+            Some(class_info) => class_info.version.clone(),
+            None => String::new()
+        }
+    }
+
+    pub fn get_state(&self, state: &mut ByteStream) -> Result<(), Error> {
+        trace!("get state");
+        let result = unsafe { self.component.get_state(state.get_shared_ptr()) };
+        if result != kResultOk {
+            return Err(Error::from("failed to get component state"));
+        }
+        Ok(())
+    }
+
+    pub fn set_state(&self, state: &mut ByteStream) -> Result<(), Error> {
+        trace!("set state");
+        let result = unsafe { self.component.set_state(state.get_shared_ptr()) };
+        if result != kResultOk {
+            return Err(Error::from("failed to set component state"));
+        }
+        Ok(())
+    }
+
     pub fn initialize(&self, host: &Host) -> Result<(), Error> {
         let host_context = host.get_context()?;
         let host_context_ptr = host_context.as_ptr();
@@ -1,11 +1,20 @@
 use log::{*};
-use std::{ptr::null_mut, sync::{Arc, Mutex}};
-use vst3_sys::{base::IUnknown, vst::{AudioBusBuffers, Event, IEditController, IEventList, IProcessContextRequirements, ProcessData, ProcessModes, SymbolicSampleSizes}};
-use crate::{audio::Audio, audio_processor::AudioProcessor, edit_controller::EditController, error::Error, events::EventList, host::Host, instance::Instance, parameters::ParameterChanges, stream::ByteStream, view::View};
+use core::slice;
+use std::{ffi::c_void, ptr::null_mut, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::Instant};
+use vst3_com::VstPtr;
+use vst3_sys::{base::{kResultOk, IUnknown}, vst::{AudioBusBuffers, BusDirections, Event, IAudioProcessor, IComponent, IEditController, IProcessContextRequirements, MediaTypes, ProcessData, RestartFlags, SymbolicSampleSizes}};
+use crate::{audio::{AudioCallbackInfo, AudioFormatInfo, SampleFormat}, audio_processor::{AudioProcessor, Tail}, config::MIDI_INPUT_TIMING, edit_controller::{EditController, ParameterEdit}, error::Error, events::{EventList, EventSender, EventSlots, InputEvents, NoteIds, OutputEvent, PayloadPool, QueuedOutputEvent}, host::Host, instance::Instance, midi::MidiMessage, midi_input::{MidiInputEvent, MidiTimingMode}, midi_mapping::MidiMapping, parameters::{ParamID, ParameterChange, ParameterChanges}, preset::{PresetFile, PresetMetaInfo}, state::PluginState, spsc::SpscQueue, stream::ByteStream, time::sample_offset_from_time, view::View};
 
 //const DEFAULT_AUDIO_BUFFER_SIZE: usize = 128;
 //const DEFAULT_SAMPLE_RATE: f64 = 48000.0;
 const ENABLE_DUMP_BUFFER: bool = false;
+const PARAMETER_QUEUE_SIZE: usize = 1024;
+const OUTPUT_EVENT_QUEUE_SIZE: usize = 1024;
+const MIDI_INPUT_QUEUE_SIZE: usize = 1024;
+const EVENT_QUEUE_SIZE: usize = 1024;
+const MAX_EVENT_SENDERS: usize = 8;
+const PAYLOAD_SLOT_COUNT: usize = 16;
+const PAYLOAD_SLOT_SIZE: usize = 64 * 1024; // fits SysEx patch dumps
 
 pub enum ProcessContextFlags {
     kPlaying = 1<<1,
@@ -23,19 +32,388 @@ pub enum ProcessContextFlags {
     kChordValid = 1 << 18
 }
 
+/// Counters of the audio thread, readable from any thread. The audio thread
+/// counts instead of logging, logging allocates.
+#[derive(Default)]
+pub struct ProcessStats {
+    pub blocks: AtomicU64,
+    pub skipped_blocks: AtomicU64, // context locked by the control thread
+    pub failed_blocks: AtomicU64, // processor returned an error
+    pub events: AtomicU64,
+    pub dropped_events: AtomicU64,
+    pub dropped_parameter_changes: AtomicU64,
+    pub unmapped_midi_messages: AtomicU64, // controllers and program changes without a parameter
+    pub max_process_time_us: AtomicU64
+}
+
+impl ProcessStats {
+    fn add_block(&self, process_time_us: u64) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+        self.max_process_time_us.fetch_max(process_time_us, Ordering::Relaxed);
+    }
+
+    /// Logs the counters, called from the control thread.
+    pub fn report(&self) {
+        debug!("process: {} blocks, {} skipped, {} failed, max process time {} us",
+            self.blocks.load(Ordering::Relaxed),
+            self.skipped_blocks.load(Ordering::Relaxed),
+            self.failed_blocks.load(Ordering::Relaxed),
+            self.max_process_time_us.load(Ordering::Relaxed));
+        debug!("process: {} events, {} dropped, {} dropped parameter changes, {} unmapped MIDI messages",
+            self.events.load(Ordering::Relaxed),
+            self.dropped_events.load(Ordering::Relaxed),
+            self.dropped_parameter_changes.load(Ordering::Relaxed),
+            self.unmapped_midi_messages.load(Ordering::Relaxed));
+    }
+}
+
+/// Audio buffers of all buses of the processor, rebuilt whenever the buses
+/// change. The first two channels of the main output bus play on the audio
+/// device, all other channels are scratch buffers and inputs are silent.
+pub struct BusBuffers {
+    inputs: Vec<AudioBusBuffers>,
+    outputs: Vec<AudioBusBuffers>,
+    channels: Vec<*mut c_void>, // channel pointers of all buses, inputs first
+    scratch: Vec<f32>, // one block per channel
+    input_channel_count: usize,
+    block_size: usize
+}
+
+impl BusBuffers {
+    /// Allocates buffers for buses with the given channel counts.
+    pub fn new(input_channel_counts: &[usize], output_channel_counts: &[usize], block_size: usize) -> Self {
+        let input_channel_count: usize = input_channel_counts.iter().sum();
+        let channel_count = input_channel_count + output_channel_counts.iter().sum::<usize>();
+
+        let mut scratch = vec![0.0f32; channel_count * block_size];
+        let mut channels = (0..channel_count).map(|channel| {
+            unsafe { scratch.as_mut_ptr().add(channel * block_size) as *mut c_void }
+        }).collect::<Vec<_>>();
+
+        let mut first_channel = 0;
+        let mut create_buses = |channel_counts: &[usize]| -> Vec<AudioBusBuffers> {
+            channel_counts.iter().map(|&count| {
+                let bus = AudioBusBuffers {
+                    num_channels: count as i32,
+                    silence_flags: 0x0,
+                    buffers: unsafe { channels.as_mut_ptr().add(first_channel) }
+                };
+                first_channel += count;
+                bus
+            }).collect()
+        };
+
+        let inputs = create_buses(input_channel_counts);
+        let outputs = create_buses(output_channel_counts);
+
+        Self {
+            inputs,
+            outputs,
+            channels,
+            scratch,
+            input_channel_count,
+            block_size
+        }
+    }
+
+    /// Points the main output at the device buffers and silences the inputs,
+    /// which the processor may have used in place.
+    fn prepare(&mut self, callback_info: &AudioCallbackInfo) {
+        self.scratch[..self.input_channel_count * self.block_size].fill(0.0);
+
+        for bus in self.inputs.iter_mut() {
+            bus.silence_flags = if bus.num_channels >= 64 { u64::MAX } else { (1u64 << bus.num_channels) - 1 };
+        }
+
+        for bus in self.outputs.iter_mut() {
+            bus.silence_flags = 0x0;
+        }
+
+        match self.outputs.first() {
+            Some(bus) => {
+                let device_buffers = [callback_info.buffer0, callback_info.buffer1];
+                for (channel, buffer) in device_buffers.iter().enumerate().take(bus.num_channels.max(0) as usize) {
+                    self.channels[self.input_channel_count + channel] = *buffer;
+                }
+            },
+            None => {}
+        };
+    }
+
+    /// Fills device channels the main output bus does not have.
+    fn finish(&self, callback_info: &AudioCallbackInfo) {
+        let channel_count = match self.outputs.first() {
+            Some(bus) => bus.num_channels,
+            None => 0
+        };
+
+        match channel_count {
+            0 => {
+                callback_info.clear();
+            },
+            1 => {
+                // mono plays on both channels, both sample formats are 32 bit wide
+                unsafe { std::ptr::copy_nonoverlapping(callback_info.buffer0 as *const u32, callback_info.buffer1 as *mut u32, callback_info.buffer_size) };
+            },
+            _ => {}
+        };
+    }
+}
+
 pub struct InstrumentContext {
     pub process_data: Box<ProcessData>,
-    pub audio_processor: AudioProcessor
+    pub audio_processor: AudioProcessor,
+    bus_buffers: BusBuffers,
+    input_param_changes: Box<ParameterChanges>,
+    parameter_queue: Arc<SpscQueue<ParameterChange>>,
+    input_events: InputEvents,
+    note_ids: NoteIds, // live MIDI input
+    midi_input_queue: Arc<SpscQueue<MidiInputEvent>>,
+    midi_timing_mode: MidiTimingMode,
+    midi_mapping: MidiMapping,
+    output_param_changes: Box<ParameterChanges>,
+    output_event_list: Box<EventList>,
+    output_parameter_queue: Arc<SpscQueue<ParameterChange>>,
+    output_event_queue: Arc<SpscQueue<QueuedOutputEvent>>,
+    output_payloads: Arc<PayloadPool>,
+    stats: Arc<ProcessStats>
 }
 
 unsafe impl Sync for InstrumentContext {}
 unsafe impl Send for InstrumentContext {}
 
+impl InstrumentContext {
+    /// Processes one block. Called on the audio thread, does not allocate or
+    /// block as long as the queue and list limits are not exceeded.
+    pub fn process(&mut self, callback_info: &AudioCallbackInfo) {
+        let start_time = Instant::now();
+
+        let audio_processor_intf = &self.audio_processor.audio_processor.clone();
+        self.dequeue_parameter_changes(callback_info.buffer_size);
+        self.input_events.dequeue(callback_info.buffer_size, &self.stats);
+        self.dequeue_midi_input(callback_info);
+
+        self.process_data.input_events = self.input_events.get_current().get_static_ptr();
+
+        let process_data = self.process_data.as_mut();
+
+        Self::process_data(audio_processor_intf, process_data, &mut self.bus_buffers, &self.stats, callback_info);
+
+        self.input_param_changes.clear();
+        self.input_events.finish_block();
+
+        self.enqueue_outputs();
+
+        self.audio_processor.advance_process_context(callback_info.buffer_size);
+
+        self.stats.add_block(start_time.elapsed().as_micros() as u64);
+    }
+
+    /// Moves the changes queued by the control thread into this block.
+    fn dequeue_parameter_changes(&mut self, buffer_size: usize) {
+        let max_offset = buffer_size.max(1) as i32 - 1;
+
+        while let Some(mut change) = self.parameter_queue.pop() {
+            change.sample_offset = change.sample_offset.clamp(0, max_offset);
+            if self.input_param_changes.add_change(&change).is_err() {
+                self.stats.dropped_parameter_changes.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Plays a controller or program change message as a change of the
+    /// parameter it is mapped to.
+    fn map_midi_controller(&mut self, message: &MidiMessage, sample_offset: i32) {
+        let (id, value) = match self.midi_mapping.map(0, message) {
+            Some(mapped) => mapped,
+            None => {
+                self.stats.unmapped_midi_messages.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+
+        let change = ParameterChange {
+            id,
+            sample_offset,
+            value
+        };
+
+        if self.input_param_changes.add_change(&change).is_err() {
+            self.stats.dropped_parameter_changes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // the controller follows the value like a processor output
+        if self.output_parameter_queue.push(change).is_err() {
+            self.stats.dropped_parameter_changes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves the MIDI input received since the last block into this block.
+    fn dequeue_midi_input(&mut self, callback_info: &AudioCallbackInfo) {
+        let buffer_size = callback_info.buffer_size as i64;
+        let sample_rate = self.audio_processor.context.audio_format.sample_rate;
+
+        while let Some(input_event) = self.midi_input_queue.peek() {
+            let sample_offset = match self.midi_timing_mode {
+                MidiTimingMode::Immediate => 0,
+                MidiTimingMode::BlockLatency => {
+                    // played one block after it arrived, measured from the start of this block
+                    let offset = sample_offset_from_time(input_event.time, callback_info.time, sample_rate) + buffer_size;
+                    if offset >= buffer_size {
+                        // arrived after this block started, keep it for the next one
+                        break;
+                    }
+                    offset.max(0)
+                }
+            };
+
+            self.midi_input_queue.pop();
+
+            let input_events = &mut self.input_events;
+            let stats = &self.stats;
+            let is_note = EventList::new_events_from_midi(&input_event.message, sample_offset as i32, &mut self.note_ids, |event| {
+                input_events.add_event(event, stats);
+            });
+
+            if !is_note {
+                self.map_midi_controller(&input_event.message, sample_offset as i32);
+            }
+        }
+    }
+
+    /// Hands the processor outputs of this block to the control thread.
+    fn enqueue_outputs(&mut self) {
+        // only the last value of a block matters for the controller
+        for index in 0..self.output_param_changes.get_queue_count() {
+            let queue = self.output_param_changes.get_queue(index).unwrap();
+            match queue.get_last_point() {
+                Some((sample_offset, value)) => {
+                    let change = ParameterChange {
+                        id: queue.get_id(),
+                        sample_offset,
+                        value
+                    };
+                    if self.output_parameter_queue.push(change).is_err() {
+                        self.stats.dropped_parameter_changes.fetch_add(1, Ordering::Relaxed);
+                    }
+                },
+                None => {}
+            };
+        }
+        self.output_param_changes.clear();
+
+        let block_position = self.audio_processor.context.process_context.project_time_samples;
+        let output_event_queue = &self.output_event_queue;
+        let output_payloads = &self.output_payloads;
+        let stats = &self.stats;
+        self.output_event_list.for_each_event(|event| {
+            let mut output_event = QueuedOutputEvent {
+                block_position,
+                event: *event
+            };
+
+            // payloads belong to the processor and are only valid during process
+            let payload = match EventList::get_payload(event) {
+                Some((ptr, size)) => {
+                    match output_payloads.store(unsafe { slice::from_raw_parts(ptr, size) }) {
+                        Ok(copy) => {
+                            EventList::set_payload(&mut output_event.event, copy, size);
+                            Some(copy)
+                        },
+                        Err(_) => {
+                            stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                },
+                None => None
+            };
+
+            if output_event_queue.push(output_event).is_err() {
+                match payload {
+                    Some(copy) => {
+                        output_payloads.release(copy);
+                    },
+                    None => {}
+                };
+                stats.dropped_events.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        self.output_event_list.clear();
+    }
+
+    /// Uses new bus buffers, the process data points to them until they are
+    /// replaced again.
+    pub fn set_bus_buffers(&mut self, bus_buffers: BusBuffers) {
+        self.bus_buffers = bus_buffers;
+
+        let process_data = self.process_data.as_mut();
+        process_data.num_inputs = self.bus_buffers.inputs.len() as i32;
+        process_data.inputs = if self.bus_buffers.inputs.is_empty() { null_mut() } else { self.bus_buffers.inputs.as_mut_ptr() };
+        process_data.num_outputs = self.bus_buffers.outputs.len() as i32;
+        process_data.outputs = if self.bus_buffers.outputs.is_empty() { null_mut() } else { self.bus_buffers.outputs.as_mut_ptr() };
+    }
+
+    fn process_data(audio_processor_intf: &vst3_com::VstPtr<dyn IAudioProcessor>, process_data: &mut ProcessData, bus_buffers: &mut BusBuffers, stats: &ProcessStats, callback_info: &AudioCallbackInfo) {
+        process_data.num_samples = callback_info.buffer_size.min(bus_buffers.block_size) as i32;
+
+        bus_buffers.prepare(callback_info);
+
+        let result = unsafe { audio_processor_intf.process(process_data as *mut _) };
+        if result != kResultOk {
+            stats.failed_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+
+        bus_buffers.finish(callback_info);
+
+        let audio_buffers = [
+            callback_info.buffer0,
+            callback_info.buffer1
+        ];
+
+        if callback_info.sample_format == SampleFormat::Int32 {
+            let buffer_size = callback_info.buffer_size;
+
+            for buffer_ptr in audio_buffers {
+                let in_buffer = unsafe { slice::from_raw_parts_mut(buffer_ptr as *mut f32, buffer_size) };
+                let out_buffer = unsafe { slice::from_raw_parts_mut(buffer_ptr as *mut u32, buffer_size) };
+
+                for i in 0..buffer_size {
+                    let a = in_buffer[i];
+
+                    let s = ((if a >= 0.0 {
+                        a
+                    } else {
+                        2.0+a
+                    }) * 32767.0) as u32;
+
+                    let s32 = (s & 0xffff) << 16;
+
+                    out_buffer[i] = s32;
+                }
+            }
+        }
+    }
+}
+
 pub struct Instrument {
+    component: VstPtr<dyn IComponent>,
+    class_id: String,
+    plugin_version: String,
+    plugin_name: String,
+    plugin_category: String,
     controller: EditController,
-    input_param_changes: Box<ParameterChanges>,
-    input_event_list: Box<EventList>,
+    parameter_queue: Arc<SpscQueue<ParameterChange>>,
+    midi_input_queue: Arc<SpscQueue<MidiInputEvent>>,
+    output_parameter_queue: Arc<SpscQueue<ParameterChange>>,
+    output_event_queue: Arc<SpscQueue<QueuedOutputEvent>>,
+    output_payloads: Arc<PayloadPool>,
+    event_slots: EventSlots,
+    event_sender: EventSender,
+    stats: Arc<ProcessStats>,
+    midi_mapping: MidiMapping,
     state_stream: Box<ByteStream>,
+    active: bool,
     context: Arc<Mutex<InstrumentContext>>
 }
 
@@ -46,7 +424,7 @@ impl Drop for Instrument {
 }
 
 impl Instrument {
-    pub fn new(instance: &Instance, host: &Host, audio: &Audio) -> Result<Self, Error> {
+    pub fn new(instance: &Instance, host: &Host, audio_format: &AudioFormatInfo, process_mode: i32) -> Result<Self, Error> {
         trace!("new");
 
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
@@ -68,35 +446,105 @@ impl Instrument {
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
 
         trace!("create audio processor");
-        let mut audio_processor = AudioProcessor::new(&instance, audio)?;
+        let mut audio_processor = AudioProcessor::new(&instance, audio_format, process_mode)?;
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
 
+        trace!("setup processing");
+        match audio_processor.setup_processing() {
+            Ok(_) => {},
+            Err(e) => {
+                warn!("{}", e.message());
+            }
+        };
+
         trace!("create edit controller");
-        let mut controller = EditController::new(&instance)?;
+        let parameter_queue = Arc::new(SpscQueue::new(PARAMETER_QUEUE_SIZE));
+
+        let mut controller = EditController::new(&instance, parameter_queue.clone())?;
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
         controller.initialize(host)?;
 
+        trace!("connect edit controller");
+        match controller.connect(instance) {
+            Ok(_) => {},
+            Err(e) => {
+                warn!("{}", e.message());
+            }
+        };
+
         trace!("create stream");
-        let state_stream = ByteStream::new();
+        let mut state_stream = ByteStream::new();
         crate::utils::trace_ref::<dyn IUnknown>(&instance.instance);
 
+        trace!("sync controller state");
+        match Self::sync_controller_state(instance, &controller, &mut state_stream) {
+            Ok(_) => {},
+            Err(e) => {
+                warn!("{}", e.message());
+            }
+        };
+
+        trace!("query midi mapping");
+        let midi_mapping = MidiMapping::query(&controller, Self::get_event_input_bus_count(&instance.component));
+
         let mut input_param_changes = ParameterChanges::new();
-        let mut input_event_list = EventList::new();
-        unsafe { input_event_list.get_event_count() };
+        let input_payloads = Arc::new(PayloadPool::new(PAYLOAD_SLOT_COUNT, PAYLOAD_SLOT_SIZE));
+        let event_slots = EventSlots::new(MAX_EVENT_SENDERS, EVENT_QUEUE_SIZE, input_payloads);
+        let event_sender = event_slots.claim()?;
+        let mut input_events = event_slots.create_input_events();
+
+        let mut output_param_changes = ParameterChanges::new();
+        let mut output_event_list = EventList::new();
 
-        let mut process_data = Self::create_process_data(&mut input_param_changes, &mut input_event_list, &mut audio_processor)?;
+        let bus_buffers = Self::create_bus_buffers(&instance.component, &audio_processor);
+        let mut process_data = Self::create_process_data(&mut input_param_changes, input_events.get_current(), &mut output_param_changes, &mut output_event_list, &mut audio_processor)?;
         process_data.context = audio_processor.context.process_context.as_mut();
 
-        let context = InstrumentContext {
+        let midi_input_queue = Arc::new(SpscQueue::new(MIDI_INPUT_QUEUE_SIZE));
+        let output_parameter_queue = Arc::new(SpscQueue::new(PARAMETER_QUEUE_SIZE));
+        let output_event_queue = Arc::new(SpscQueue::new(OUTPUT_EVENT_QUEUE_SIZE));
+
+        let output_payloads = Arc::new(PayloadPool::new(PAYLOAD_SLOT_COUNT, PAYLOAD_SLOT_SIZE));
+        let stats = Arc::new(ProcessStats::default());
+
+        let mut context = InstrumentContext {
             process_data: Box::new(process_data),
-            audio_processor
+            audio_processor,
+            bus_buffers: BusBuffers::new(&[], &[], 0),
+            input_param_changes,
+            parameter_queue: parameter_queue.clone(),
+            input_events,
+            note_ids: NoteIds::new(),
+            midi_input_queue: midi_input_queue.clone(),
+            midi_timing_mode: MidiTimingMode::from_name(MIDI_INPUT_TIMING).unwrap_or(MidiTimingMode::BlockLatency),
+            midi_mapping: midi_mapping.clone(),
+            output_param_changes,
+            output_event_list,
+            output_parameter_queue: output_parameter_queue.clone(),
+            output_event_queue: output_event_queue.clone(),
+            output_payloads: output_payloads.clone(),
+            stats: stats.clone()
         };
+        context.set_bus_buffers(bus_buffers);
 
         let instrument = Self {
+            component: instance.component.clone(),
+            class_id: instance.class_id().to_string(),
+            plugin_version: instance.get_plugin_version(),
+            plugin_name: instance.get_class_info().map(|c| c.name.clone()).unwrap_or_default(),
+            plugin_category: instance.get_class_info().map(|c| c.sub_categories.join("|")).unwrap_or_default(),
             controller,
-            input_param_changes,
-            input_event_list,
+            parameter_queue,
+            midi_input_queue,
+            output_parameter_queue,
+            output_event_queue,
+            output_payloads,
+            event_slots,
+            event_sender,
+            stats,
+            midi_mapping,
             state_stream,
+            active: false,
             context: Arc::new(Mutex::new(context))
         };
 
@@ -119,29 +567,344 @@ impl Instrument {
 
     }
 
-    /*
-    pub fn init(&mut self, samples_per_block: usize, sample_rate: f64) -> Result<(), Error> {
-        trace!("init");
-        let _ = self.controller.set_component_state(&mut self.state_stream);
+    /// Passes the component state to a separate controller, the SDK
+    /// requires this after creation and whenever the component state is set.
+    fn sync_controller_state(instance: &Instance, controller: &EditController, state_stream: &mut ByteStream) -> Result<(), Error> {
+        if !controller.is_instance {
+            // single component, the controller shares the state
+            return Ok(());
+        }
+
+        state_stream.clear();
+        instance.get_state(state_stream)?;
+        state_stream.rewind();
+        controller.set_component_state(state_stream)
+    }
+
+    pub fn get_state(&mut self) -> Result<PluginState, Error> {
+        trace!("get state");
+
+        self.state_stream.clear();
+        let result = unsafe { self.component.get_state(self.state_stream.get_shared_ptr()) };
+        if result != kResultOk {
+            return Err(Error::from("failed to get component state"));
+        }
+        let component_state = self.state_stream.to_vec();
+
+        self.state_stream.clear();
+        let controller_state = match self.controller.get_state(&mut self.state_stream) {
+            Ok(_) => self.state_stream.to_vec(),
+            Err(_) => {
+                // controllers without own state are valid
+                Vec::new()
+            }
+        };
+
+        Ok(PluginState {
+            class_id: self.class_id.clone(),
+            plugin_version: self.plugin_version.clone(),
+            component_state,
+            controller_state
+        })
+    }
 
+    pub fn set_state(&mut self, state: &PluginState) -> Result<(), Error> {
+        trace!("set state");
+
+        if state.class_id != self.class_id {
+            return Err(Error::from(format!("state belongs to class {}, not {}", state.class_id, self.class_id)));
+        }
+
+        if state.plugin_version != self.plugin_version {
+            warn!("state was saved with plugin version '{}', loading into '{}'", state.plugin_version, self.plugin_version);
+        }
+
+        let mut component_stream = ByteStream::from_vec(state.component_state.clone());
+        let result = unsafe { self.component.set_state(component_stream.get_shared_ptr()) };
+        if result != kResultOk {
+            return Err(Error::from("failed to set component state"));
+        }
+
+        if self.controller.is_instance {
+            component_stream.rewind();
+            self.controller.set_component_state(&mut component_stream)?;
+        }
+
+        if !state.controller_state.is_empty() {
+            let mut controller_stream = ByteStream::from_vec(state.controller_state.clone());
+            self.controller.set_state(&mut controller_stream)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save_state(&mut self, path: &str) -> Result<(), Error> {
+        trace!("save state");
+        let state = self.get_state()?;
+        state.save(path)
+    }
+
+    pub fn load_state(&mut self, path: &str) -> Result<(), Error> {
+        trace!("load state");
+        let state = PluginState::load(path)?;
+        self.set_state(&state)
+    }
+
+    pub fn save_preset(&mut self, path: &str, meta_info: &PresetMetaInfo) -> Result<(), Error> {
+        trace!("save preset");
+
+        let state = self.get_state()?;
+
+        let mut meta_info = meta_info.clone();
+        if meta_info.plugin_name.is_empty() {
+            meta_info.plugin_name = self.plugin_name.clone();
+        }
+        if meta_info.plugin_category.is_empty() {
+            meta_info.plugin_category = self.plugin_category.clone();
+        }
+
+        let preset = PresetFile {
+            class_id: state.class_id,
+            component_state: state.component_state,
+            controller_state: state.controller_state,
+            meta_info: Some(meta_info)
+        };
+
+        preset.save(path)
+    }
+
+    pub fn load_preset(&mut self, path: &str) -> Result<(), Error> {
+        trace!("load preset");
+
+        let preset = PresetFile::load(path)?;
+
+        if preset.class_id != self.class_id {
+            return Err(Error::from(format!("preset belongs to class {}, not {}", preset.class_id, self.class_id)));
+        }
+
+        // presets carry no plugin version
+        let state = PluginState {
+            class_id: preset.class_id,
+            plugin_version: self.plugin_version.clone(),
+            component_state: preset.component_state,
+            controller_state: preset.controller_state
+        };
+
+        self.set_state(&state)
+    }
+
+    /// Queues an event for the next process call, see `EventSender::send`.
+    pub fn push_event(&mut self, event: Event) -> Result<(), Error> {
+        self.event_sender.send(event)
+    }
+
+    /// Returns a sender for another thread that plays events. The queue of
+    /// the sender is released when it is dropped.
+    pub fn create_event_sender(&self) -> Result<EventSender, Error> {
+        self.event_slots.claim()
+    }
+
+    pub fn get_process_stats(&self) -> Arc<ProcessStats> {
+        self.stats.clone()
+    }
+
+    pub fn set_midi_timing_mode(&mut self, mode: MidiTimingMode) {
         match self.context.lock() {
             Ok(mut context) => {
-                context.audio_processor.setup_processing(samples_per_block, sample_rate)?;
-                context.audio_processor.set_processing(false)?;
-            }
+                context.midi_timing_mode = mode;
+            },
             Err(_) => {}
         };
+    }
+
+    /// Parameter and normalized value a MIDI controller message changes.
+    pub fn map_midi_controller(&self, message: &MidiMessage) -> Option<(ParamID, f64)> {
+        self.midi_mapping.map(0, message)
+    }
+
+    fn get_event_input_bus_count(component: &VstPtr<dyn IComponent>) -> usize {
+        let count = unsafe { component.get_bus_count(MediaTypes::kEvent as i32, BusDirections::kInput as i32) };
+        count.max(0) as usize
+    }
+
+    /// Queries the MIDI controller assignments again after the plugin changed them.
+    fn update_midi_mapping(&mut self) -> Result<(), Error> {
+        self.midi_mapping = MidiMapping::query(&self.controller, Self::get_event_input_bus_count(&self.component));
+
+        match self.context.lock() {
+            Ok(mut context) => {
+                context.midi_mapping = self.midi_mapping.clone();
+                Ok(())
+            },
+            Err(_) => Err(Error::from("failed to lock instrument context"))
+        }
+    }
+
+    /// Queue for live MIDI input, the single producer is the MIDI input thread.
+    pub fn get_midi_input_queue(&self) -> Arc<SpscQueue<MidiInputEvent>> {
+        self.midi_input_queue.clone()
+    }
+
+    /// Queues a parameter change for the next process call and updates the
+    /// controller. The sample offset is relative to the start of that block.
+    pub fn set_parameter(&mut self, id: ParamID, value: f64, sample_offset: i32) -> Result<(), Error> {
+        let change = ParameterChange {
+            id,
+            sample_offset,
+            value
+        };
+
+        match self.parameter_queue.push(change) {
+            Ok(_) => {},
+            Err(_) => {
+                return Err(Error::from("parameter queue overflow"));
+            }
+        };
+
+        self.controller.set_param_normalized(id, value)
+    }
+
+    pub fn get_parameter_count(&self) -> i32 {
+        self.controller.get_parameter_count()
+    }
+
+    pub fn dispatch_messages(&self) {
+        self.controller.dispatch_messages();
+    }
+
+    pub fn take_completed_edits(&self) -> Vec<ParameterEdit> {
+        self.controller.component_handler.take_completed_edits()
+    }
+
+    pub fn take_restart_flags(&self) -> i32 {
+        self.controller.component_handler.take_restart_flags()
+    }
+
+    /// Applies a restart request of the component. Buses, latency and
+    /// reloads need a new setup: processing stops, the component is
+    /// deactivated, the buses are queried and the processor set up again
+    /// before it is reactivated. A reload keeps the instance and restores
+    /// its state afterwards. MIDI controller assignments are queried again
+    /// when they or the buses changed. Must be called from the control
+    /// thread, the audio thread skips its blocks while the context is locked.
+    pub fn restart_component(&mut self, flags: i32) -> Result<(), Error> {
+        let mapping_flags = RestartFlags::kMidiCCAssignmentChanged as i32 | RestartFlags::kIoChanged as i32 | RestartFlags::kReloadComponent as i32;
+        if flags & mapping_flags != 0 {
+            self.update_midi_mapping()?;
+        }
+
+        let reconfigure_flags = RestartFlags::kReloadComponent as i32 | RestartFlags::kIoChanged as i32 | RestartFlags::kLatencyChanged as i32;
+        if flags & reconfigure_flags == 0 {
+            return Ok(());
+        }
+
+        trace!("restart component {:#x}", flags);
+
+        let reload_state = if flags & RestartFlags::kReloadComponent as i32 != 0 {
+            Some(self.get_state()?)
+        } else {
+            None
+        };
+
+        let mut context = match self.context.lock() {
+            Ok(context) => context,
+            Err(_) => {
+                return Err(Error::from("failed to lock instrument context"));
+            }
+        };
+
+        let was_processing = context.audio_processor.is_processing();
+        let was_active = self.active;
+        let old_latency = context.audio_processor.get_latency();
+
+        if was_processing {
+            context.audio_processor.set_processing(false)?;
+        }
+
+        // the setup may only change while the component is inactive
+        let _ = unsafe { self.component.set_active(0) };
+        self.active = false;
+
+        let bus_buffers = Self::create_bus_buffers(&self.component, &context.audio_processor);
+        context.set_bus_buffers(bus_buffers);
+
+        context.audio_processor.setup_processing()?;
+
+        if was_active {
+            let result = unsafe { self.component.set_active(1) };
+            if result != kResultOk {
+                return Err(Error::from("failed to activate component"));
+            }
+            self.active = true;
+        }
+
+        if was_processing {
+            // requeries the latency
+            context.audio_processor.set_processing(true)?;
+
+            let latency = context.audio_processor.get_latency();
+            if latency != old_latency {
+                debug!("latency changed from {} to {} samples", old_latency, latency);
+            }
+        }
+
+        drop(context);
+
+        match reload_state {
+            Some(state) => {
+                self.set_state(&state)?;
+            },
+            None => {}
+        };
 
         Ok(())
     }
-    */
 
-    pub fn push_event(&mut self, event: Event) -> Result<(), Error> {
-        self.input_event_list.push_event(event)
+    /// Activates or deactivates the component, processing must be off.
+    pub fn set_active(&mut self, active: bool) -> Result<(), Error> {
+        let result = unsafe { self.component.set_active(if active { 1 } else { 0 }) };
+        if result != kResultOk {
+            return Err(Error::from("failed to activate component"));
+        }
+        self.active = active;
+        Ok(())
     }
 
-    pub fn clear_events(&mut self) -> Result<(), Error> {
-        self.input_event_list.clear()
+    /// Queries the audio buses and their speaker arrangements and allocates
+    /// buffers for them.
+    fn create_bus_buffers(component: &VstPtr<dyn IComponent>, audio_processor: &AudioProcessor) -> BusBuffers {
+        let get_channel_counts = |direction: i32| -> Vec<usize> {
+            let bus_count = unsafe { component.get_bus_count(MediaTypes::kAudio as i32, direction) };
+            (0..bus_count.max(0)).map(|index| {
+                audio_processor.get_bus_arrangement(direction, index).count_ones() as usize
+            }).collect()
+        };
+
+        let input_channel_counts = get_channel_counts(BusDirections::kInput as i32);
+        let output_channel_counts = get_channel_counts(BusDirections::kOutput as i32);
+        debug!("audio buses: input channels {:?}, output channels {:?}", input_channel_counts, output_channel_counts);
+
+        BusBuffers::new(&input_channel_counts, &output_channel_counts, audio_processor.get_format().buffer_size)
+    }
+
+    /// Forwards parameter values reported by the processor to the controller
+    /// and returns the output events. Call periodically from the control thread.
+    pub fn process_outputs(&mut self) -> Vec<OutputEvent> {
+        while let Some(change) = self.output_parameter_queue.pop() {
+            match self.controller.set_param_normalized(change.id, change.value) {
+                Ok(_) => {},
+                Err(_) => {
+                    trace!("failed to update controller parameter {}", change.id);
+                }
+            };
+        }
+
+        let mut events = Vec::<OutputEvent>::new();
+        while let Some(queued) = self.output_event_queue.pop() {
+            events.push(OutputEvent::new(&queued, &self.output_payloads));
+        }
+
+        events
     }
 
     pub fn create_view(&self) -> Result<View, Error> {
@@ -154,33 +917,22 @@ impl Instrument {
         &self.context
     }
 
-    fn create_process_data(input_param_changes: &mut ParameterChanges, input_event_list: &mut EventList, audio_processor: &mut AudioProcessor) -> Result<ProcessData, Error> {
-        let audio_buffers = [
-            null_mut(),
-            null_mut()
-        ].as_mut_ptr();
-
-        let mut output_buffers = AudioBusBuffers {
-            num_channels: 2,
-            silence_flags: 0x0,
-            buffers: audio_buffers
-        };
-
+    fn create_process_data(input_param_changes: &mut ParameterChanges, input_event_list: &mut EventList, output_param_changes: &mut ParameterChanges, output_event_list: &mut EventList, audio_processor: &mut AudioProcessor) -> Result<ProcessData, Error> {
         let input_param_changes = input_param_changes.get_static_ptr();
-        let output_param_changes = ParameterChanges::get_null_ptr();
+        let output_param_changes = output_param_changes.get_static_ptr();
         let input_events = input_event_list.get_static_ptr();
-        let output_events = EventList::get_null_ptr();
+        let output_events = output_event_list.get_static_ptr();
 
         let audio_format = audio_processor.get_format();
 
         let data = ProcessData {
-            process_mode: ProcessModes::kRealtime as i32,
+            process_mode: audio_processor.get_process_mode(),
             symbolic_sample_size: SymbolicSampleSizes::kSample32 as i32,
             num_samples: audio_format.buffer_size as i32,
-            num_inputs: 0,
-            num_outputs: 1,
+            num_inputs: 0, // set with the bus buffers
+            num_outputs: 0,
             inputs: null_mut(),
-            outputs: &mut output_buffers,
+            outputs: null_mut(),
             input_param_changes,
             output_param_changes,
             input_events,
@@ -204,6 +956,13 @@ impl Instrument {
         Ok(())
     }
 
+    pub fn get_tail(&self) -> Tail {
+        match self.context.lock() {
+            Ok(context) => context.audio_processor.get_tail(),
+            Err(_) => Tail::None
+        }
+    }
+
     /*
     fn dump_buffer(&self, buffer: &[u32; DEFAULT_AUDIO_BUFFER_SIZE]) {
         print!("[");
@@ -8,11 +8,18 @@
 
 use application::Application;
 
-use config::{ASIO_DEVICE_NAME, VST_CLSID};
+use audio::AudioBackendType;
+use config::{ASIO_DEVICE_NAME, AUDIO_BACKEND, NULL_DEVICE_NAME, VST_CLASS};
 use error::Error;
 use log::{*};
 use logger::DefaultLogger;
+use midi_input::{MidiInput, MidiTimingMode};
+use preset::{PresetFile, PresetMetaInfo};
+use render::RenderSettings;
+use scanner::{Scanner, SCAN_COMMAND};
+use stress::StressSettings;
 
+#[cfg(windows)]
 mod constants;
 mod logger;
 mod utils;
@@ -21,20 +28,42 @@ mod config;
 mod application;
 mod instance;
 mod plugin;
+mod bundle;
+mod module_info;
 mod registry;
+mod scanner;
 mod edit_controller;
+mod connection;
+mod message;
 mod host;
 mod audio_processor;
 mod parameters;
+mod spsc;
 mod events;
 mod instrument;
 mod session;
 mod stream;
+mod state;
+mod preset;
 mod view;
 mod context;
 mod audio;
+#[cfg(windows)]
+mod audio_asio;
+mod audio_null;
 mod time;
+mod midi;
+mod midi_file;
+mod midi_input;
+#[cfg(target_os = "linux")]
+mod midi_input_alsa;
+mod midi_mapping;
+mod wav;
+mod render;
+mod stress;
+#[cfg(windows)]
 mod painter;
+#[cfg(windows)]
 mod window;
 
 pub const fn default_logger() -> DefaultLogger {
@@ -46,15 +75,232 @@ pub fn init_logger(logger: &'static dyn log::Log, log_level: LogLevel) {
     crate::logger::init(logger, log_level)
 }
 
+struct Options {
+    audio_backend: AudioBackendType,
+    headless: bool,
+    class: String,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    preset_name: Option<String>,
+    preset_author: Option<String>,
+    midi_input: Option<String>,
+    list_midi: bool,
+    midi_timing: Option<MidiTimingMode>,
+    render: Option<RenderSettings>,
+    stress: Option<StressSettings>
+}
+
+fn parse_options() -> Result<Options, Error> {
+    let mut options = Options {
+        audio_backend: AudioBackendType::from_name(AUDIO_BACKEND).unwrap_or(AudioBackendType::Asio),
+        headless: false,
+        class: VST_CLASS.to_string(),
+        load_state: None,
+        save_state: None,
+        preset_name: None,
+        preset_author: None,
+        midi_input: None,
+        list_midi: false,
+        midi_timing: None,
+        render: None,
+        stress: None
+    };
+
+    let mut args = std::env::args().skip(1).peekable();
+
+    if args.peek().map(|arg| arg == "render").unwrap_or(false) {
+        args.next();
+
+        let midi_path = args.next();
+        let wav_path = args.next();
+
+        if midi_path.is_none() || wav_path.is_none() {
+            return Err(Error::from("usage: keystone render <midi file> <wav file> [--class <class name or id>]"));
+        }
+
+        options.render = Some(RenderSettings::new(VST_CLASS, &midi_path.unwrap(), &wav_path.unwrap()));
+    }
+
+    if args.peek().map(|arg| arg == "stress").unwrap_or(false) {
+        args.next();
+        options.stress = Some(StressSettings::new(VST_CLASS));
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--audio" => {
+                let name = args.next().unwrap_or_default();
+                options.audio_backend = match AudioBackendType::from_name(&name) {
+                    Some(backend_type) => backend_type,
+                    None => {
+                        return Err(Error::from(format!("unknown audio backend '{}'", name)));
+                    }
+                };
+            },
+            "--headless" => {
+                options.headless = true;
+            },
+            "--load-state" => {
+                options.load_state = args.next();
+            },
+            "--save-state" => {
+                options.save_state = args.next();
+            },
+            "--preset-name" => {
+                options.preset_name = args.next();
+            },
+            "--preset-author" => {
+                options.preset_author = args.next();
+            },
+            "--midi" => {
+                options.midi_input = Some(args.next().unwrap_or_default());
+            },
+            "--midi-timing" => {
+                let name = args.next().unwrap_or_default();
+                options.midi_timing = match MidiTimingMode::from_name(&name) {
+                    Some(mode) => Some(mode),
+                    None => {
+                        return Err(Error::from(format!("unknown MIDI timing '{}'", name)));
+                    }
+                };
+            },
+            "--list-midi" => {
+                options.list_midi = true;
+            },
+            "--class" => {
+                options.class = args.next().unwrap_or_default();
+                match options.render.as_mut() {
+                    Some(settings) => {
+                        settings.class_id = options.class.clone();
+                    },
+                    None => {}
+                };
+                match options.stress.as_mut() {
+                    Some(settings) => {
+                        settings.class_id = options.class.clone();
+                    },
+                    None => {}
+                };
+            },
+            "--threads" => {
+                let threads = args.next().unwrap_or_default();
+                match (options.stress.as_mut(), threads.parse::<usize>()) {
+                    (Some(settings), Ok(threads)) => {
+                        settings.threads = threads;
+                    },
+                    _ => {
+                        return Err(Error::from("usage: keystone stress [--class <class name or id>] [--threads <count>] [--seconds <run time>]"));
+                    }
+                };
+            },
+            "--seconds" => {
+                let seconds = args.next().unwrap_or_default();
+                match (options.stress.as_mut(), seconds.parse::<u64>()) {
+                    (Some(settings), Ok(seconds)) => {
+                        settings.run_time = std::time::Duration::from_secs(seconds);
+                    },
+                    _ => {
+                        return Err(Error::from("usage: keystone stress [--class <class name or id>] [--threads <count>] [--seconds <run time>]"));
+                    }
+                };
+            },
+            _ => {
+                return Err(Error::from(format!("unknown argument '{}'", arg)));
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+fn get_file_stem(path: &str) -> String {
+    match std::path::Path::new(path).file_stem() {
+        Some(stem) => stem.to_string_lossy().to_string(),
+        None => String::new()
+    }
+}
+
 fn run() -> Result<(), Error> {
     trace!("run");
 
+    let options = parse_options()?;
+
+    if options.list_midi {
+        MidiInput::list_ports();
+        return Ok(());
+    }
+
     let mut app = Application::new()?;
 
-    app.create_audio(ASIO_DEVICE_NAME)?;
-    app.create_window()?;
-    app.load_instrument(VST_CLSID)?;
+    match options.render.as_ref() {
+        Some(settings) => {
+            let result = app.render(settings);
+            app.dispose();
+            return result;
+        },
+        None => {}
+    };
+
+    match options.stress.as_ref() {
+        Some(settings) => {
+            let result = app.stress(settings);
+            app.dispose();
+            return result;
+        },
+        None => {}
+    };
+
+    let device_name = match options.audio_backend {
+        AudioBackendType::Asio => ASIO_DEVICE_NAME,
+        AudioBackendType::Null => NULL_DEVICE_NAME
+    };
+
+    app.create_audio(options.audio_backend, device_name)?;
+
+    match options.midi_input.as_ref() {
+        Some(name) => {
+            app.create_midi_input(name)?;
+        },
+        None => {}
+    };
+
+    if !options.headless {
+        app.create_window()?;
+    }
+    app.load_instrument(&options.class)?;
+    match options.midi_timing {
+        Some(mode) => {
+            app.set_midi_timing_mode(mode);
+        },
+        None => {}
+    };
+    match options.load_state.as_ref() {
+        Some(path) => {
+            if PresetFile::is_preset_path(path) {
+                app.load_preset(path)?;
+            } else {
+                app.load_state(path)?;
+            }
+        },
+        None => {}
+    };
     app.run()?;
+    match options.save_state.as_ref() {
+        Some(path) => {
+            if PresetFile::is_preset_path(path) {
+                let meta_info = PresetMetaInfo {
+                    name: options.preset_name.clone().unwrap_or_else(|| get_file_stem(path)),
+                    author: options.preset_author.clone().unwrap_or_default(),
+                    ..Default::default()
+                };
+                app.save_preset(path, &meta_info)?;
+            } else {
+                app.save_state(path)?;
+            }
+        },
+        None => {}
+    };
+    app.close_midi_input()?;
     app.unload_instrument()?;
     app.close_window()?;
     app.close_audio()?;
@@ -65,6 +311,13 @@ fn run() -> Result<(), Error> {
 
 fn main() {
 
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == SCAN_COMMAND {
+        // scanner child process, stdout is reserved for the scan result
+        init_logger(&DefaultLogger, LogLevel::Off);
+        std::process::exit(Scanner::run_scan_command(&args[2]));
+    }
+
     init_logger(&DefaultLogger, LogLevel::Trace);
 
     debug!("Keystone - STARTED");
@@ -0,0 +1,255 @@
+//!
+//! Host messages
+//!
+//! `IMessage` and `IAttributeList` objects handed out by
+//! `IHostApplication::create_instance`. Plugins use them to send data
+//! between component and controller over `IConnectionPoint`.
+//!
+
+use std::{collections::HashMap, ffi::{c_void, CStr, CString}, ptr::null, sync::Mutex};
+
+use vst3_com::{ComInterface, VstPtr, IID};
+use vst3_sys::{base::{kInvalidArgument, kResultFalse, kResultOk, kResultTrue, tchar, tresult, FIDString, IUnknown}, utils::StaticVstPtr, vst::{AttrID, IAttributeList, IMessage}, VST3};
+
+use crate::host::Host;
+
+pub fn register_interfaces(host: &Host) {
+    host.register_interface_support::<dyn IMessage>();
+    host.register_interface_support::<dyn IAttributeList>();
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Int(i64),
+    Float(f64),
+    String(Vec<u16>), // without terminator
+    Binary(Vec<u8>)
+}
+
+#[VST3(implements(IAttributeList))]
+pub struct HostAttributeList {
+    attributes: Mutex<HashMap<String, AttributeValue>>
+}
+
+impl HostAttributeList {
+    pub fn new() -> Box<Self> {
+        let instance = Self::allocate(Mutex::new(HashMap::new()));
+        instance
+    }
+
+    pub fn get(&self, id: &str) -> Option<AttributeValue> {
+        self.attributes.lock().unwrap().get(id).cloned()
+    }
+
+    pub fn set(&self, id: &str, value: AttributeValue) {
+        self.attributes.lock().unwrap().insert(id.to_string(), value);
+    }
+
+    unsafe fn attribute_id(id: AttrID) -> Option<String> {
+        if id.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(id).to_string_lossy().to_string())
+    }
+}
+
+impl IAttributeList for HostAttributeList {
+    unsafe fn set_int(&self, id: AttrID, value: i64) -> tresult {
+        match Self::attribute_id(id) {
+            Some(id) => {
+                self.set(&id, AttributeValue::Int(value));
+                kResultOk
+            },
+            None => kInvalidArgument
+        }
+    }
+
+    unsafe fn get_int(&self, id: AttrID, value: *mut i64) -> tresult {
+        if value.is_null() {
+            return kInvalidArgument;
+        }
+        match Self::attribute_id(id).and_then(|id| self.get(&id)) {
+            Some(AttributeValue::Int(v)) => {
+                *value = v;
+                kResultOk
+            },
+            _ => kResultFalse
+        }
+    }
+
+    unsafe fn set_float(&self, id: AttrID, value: f64) -> tresult {
+        match Self::attribute_id(id) {
+            Some(id) => {
+                self.set(&id, AttributeValue::Float(value));
+                kResultOk
+            },
+            None => kInvalidArgument
+        }
+    }
+
+    unsafe fn get_float(&self, id: AttrID, value: *mut f64) -> tresult {
+        if value.is_null() {
+            return kInvalidArgument;
+        }
+        match Self::attribute_id(id).and_then(|id| self.get(&id)) {
+            Some(AttributeValue::Float(v)) => {
+                *value = v;
+                kResultOk
+            },
+            _ => kResultFalse
+        }
+    }
+
+    unsafe fn set_string(&self, id: AttrID, value: *const tchar, _size: u32) -> tresult {
+        // the SDK signature has no size argument, the string is zero terminated
+        let id = match Self::attribute_id(id) {
+            Some(id) => id,
+            None => {
+                return kInvalidArgument;
+            }
+        };
+
+        if value.is_null() {
+            return kInvalidArgument;
+        }
+
+        let mut length = 0;
+        while *value.add(length) != 0 {
+            length += 1;
+        }
+
+        let chars = std::slice::from_raw_parts(value as *const u16, length).to_vec();
+        self.set(&id, AttributeValue::String(chars));
+
+        kResultOk
+    }
+
+    unsafe fn get_string(&self, id: AttrID, value: *mut tchar, size: u32) -> tresult {
+        // size is in bytes
+        let max_chars = size as usize / std::mem::size_of::<tchar>();
+        if value.is_null() || max_chars == 0 {
+            return kInvalidArgument;
+        }
+
+        match Self::attribute_id(id).and_then(|id| self.get(&id)) {
+            Some(AttributeValue::String(chars)) => {
+                let length = chars.len().min(max_chars - 1);
+                std::ptr::copy_nonoverlapping(chars.as_ptr() as *const tchar, value, length);
+                *value.add(length) = 0;
+                kResultOk
+            },
+            _ => kResultFalse
+        }
+    }
+
+    unsafe fn set_binary(&self, id: AttrID, ptr: *const c_void, size: u32) -> tresult {
+        let id = match Self::attribute_id(id) {
+            Some(id) => id,
+            None => {
+                return kInvalidArgument;
+            }
+        };
+
+        let data = if ptr.is_null() || size == 0 {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(ptr as *const u8, size as usize).to_vec()
+        };
+
+        self.set(&id, AttributeValue::Binary(data));
+
+        kResultOk
+    }
+
+    unsafe fn get_binary(&self, id: AttrID, ptr: *const *mut c_void, size: *mut u32) -> tresult {
+        if ptr.is_null() || size.is_null() {
+            return kInvalidArgument;
+        }
+
+        let id = match Self::attribute_id(id) {
+            Some(id) => id,
+            None => {
+                return kInvalidArgument;
+            }
+        };
+
+        // the data stays valid until the attribute is changed or the list is released
+        let attributes = self.attributes.lock().unwrap();
+        match attributes.get(&id) {
+            Some(AttributeValue::Binary(data)) => {
+                *(ptr as *mut *const c_void) = data.as_ptr() as *const c_void;
+                *size = data.len() as u32;
+                kResultOk
+            },
+            _ => kResultFalse
+        }
+    }
+}
+
+#[VST3(implements(IMessage))]
+pub struct HostMessage {
+    message_id: Mutex<Option<CString>>,
+    attributes: VstPtr<dyn IAttributeList>
+}
+
+impl HostMessage {
+    pub fn new() -> Box<Self> {
+        let attribute_list = Box::into_raw(HostAttributeList::new());
+        let attributes = unsafe { VstPtr::<dyn IAttributeList>::owned(attribute_list as *mut *mut _).unwrap() };
+        let instance = Self::allocate(Mutex::new(None), attributes);
+        instance
+    }
+}
+
+impl IMessage for HostMessage {
+    unsafe fn get_message_id(&self) -> FIDString {
+        match self.message_id.lock().unwrap().as_ref() {
+            Some(message_id) => message_id.as_ptr(),
+            None => null()
+        }
+    }
+
+    unsafe fn set_message_id(&self, id: FIDString) {
+        let message_id = if id.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(id).to_owned())
+        };
+        *self.message_id.lock().unwrap() = message_id;
+    }
+
+    unsafe fn get_attributes(&self) -> StaticVstPtr<dyn IAttributeList> {
+        // not reference counted, valid as long as the message
+        std::mem::transmute(self.attributes.as_ptr())
+    }
+}
+
+/// Creates a host object for `IHostApplication::create_instance`. The class
+/// id and interface id are both the interface IID of the requested object.
+pub unsafe fn create_instance(cid: &IID, iid: &IID, obj: *mut *mut c_void) -> tresult {
+    let instance: *mut c_void = if cid == &<dyn IMessage as ComInterface>::IID {
+        Box::into_raw(HostMessage::new()) as *mut c_void
+    } else if cid == &<dyn IAttributeList as ComInterface>::IID {
+        Box::into_raw(HostAttributeList::new()) as *mut c_void
+    } else {
+        *obj = std::ptr::null_mut();
+        return kResultFalse;
+    };
+
+    // the first field of both objects is their interface vtable pointer
+    let unknown = match VstPtr::<dyn IUnknown>::owned(instance as *mut *mut _) {
+        Some(unknown) => unknown,
+        None => {
+            return kResultFalse;
+        }
+    };
+
+    // the caller owns the reference added by query_interface
+    let result = unknown.query_interface(iid, obj);
+    if result != kResultOk && result != kResultTrue {
+        *obj = std::ptr::null_mut();
+        return kResultFalse;
+    }
+
+    kResultOk
+}
@@ -0,0 +1,231 @@
+//!
+//! MIDI messages
+//!
+
+pub const STATUS_NOTE_OFF: u8 = 0x80;
+pub const STATUS_NOTE_ON: u8 = 0x90;
+pub const STATUS_POLY_PRESSURE: u8 = 0xA0;
+pub const STATUS_CONTROL_CHANGE: u8 = 0xB0;
+pub const STATUS_PROGRAM_CHANGE: u8 = 0xC0;
+pub const STATUS_CHANNEL_PRESSURE: u8 = 0xD0;
+pub const STATUS_PITCH_BEND: u8 = 0xE0;
+pub const STATUS_SYSTEM: u8 = 0xF0;
+
+pub const PITCH_BEND_CENTER: u16 = 0x2000;
+pub const PITCH_BEND_MAX: u16 = 0x3FFF;
+
+pub const MIDI_CHANNEL_COUNT: usize = 16;
+
+// VST3 controller numbers, the MIDI controllers followed by pseudo controllers
+pub const CTRL_AFTER_TOUCH: i16 = 128; // channel pressure
+pub const CTRL_PITCH_BEND: i16 = 129;
+pub const CTRL_COUNT: usize = 130;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, pitch: u8, velocity: u8 },
+    NoteOn { channel: u8, pitch: u8, velocity: u8 },
+    PolyPressure { channel: u8, pitch: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    PitchBend { channel: u8, value: u16 }
+}
+
+impl MidiMessage {
+
+    /// Number of data bytes following a channel voice status byte,
+    /// or None for system messages.
+    pub fn data_length(status: u8) -> Option<usize> {
+        match status & 0xF0 {
+            STATUS_PROGRAM_CHANGE | STATUS_CHANNEL_PRESSURE => Some(1),
+            STATUS_NOTE_OFF | STATUS_NOTE_ON | STATUS_POLY_PRESSURE | STATUS_CONTROL_CHANGE | STATUS_PITCH_BEND => Some(2),
+            _ => None
+        }
+    }
+
+    pub fn from_bytes(status: u8, data: &[u8]) -> Option<Self> {
+
+        let len = Self::data_length(status)?;
+        if data.len() < len {
+            return None;
+        }
+
+        let channel = status & 0x0F;
+        let data0 = data[0] & 0x7F;
+        let data1 = if len > 1 { data[1] & 0x7F } else { 0 };
+
+        let message = match status & 0xF0 {
+            STATUS_NOTE_OFF => Self::NoteOff { channel, pitch: data0, velocity: data1 },
+            STATUS_NOTE_ON => {
+                if data1 == 0 {
+                    // note on with zero velocity is a note off
+                    Self::NoteOff { channel, pitch: data0, velocity: 0 }
+                } else {
+                    Self::NoteOn { channel, pitch: data0, velocity: data1 }
+                }
+            },
+            STATUS_POLY_PRESSURE => Self::PolyPressure { channel, pitch: data0, pressure: data1 },
+            STATUS_CONTROL_CHANGE => Self::ControlChange { channel, controller: data0, value: data1 },
+            STATUS_PROGRAM_CHANGE => Self::ProgramChange { channel, program: data0 },
+            STATUS_CHANNEL_PRESSURE => Self::ChannelPressure { channel, pressure: data0 },
+            STATUS_PITCH_BEND => Self::PitchBend { channel, value: ((data1 as u16) << 7) | (data0 as u16) },
+            _ => {
+                return None;
+            }
+        };
+
+        Some(message)
+    }
+
+    /// VST3 controller number and normalized value of controller messages.
+    pub fn controller_value(&self) -> Option<(i16, f64)> {
+        match *self {
+            Self::ControlChange { controller, value, .. } => Some((controller as i16, value as f64 / 127.0)),
+            Self::ChannelPressure { pressure, .. } => Some((CTRL_AFTER_TOUCH, pressure as f64 / 127.0)),
+            Self::PitchBend { value, .. } => Some((CTRL_PITCH_BEND, value as f64 / PITCH_BEND_MAX as f64)),
+            _ => None
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        match *self {
+            Self::NoteOff { channel, .. } => channel,
+            Self::NoteOn { channel, .. } => channel,
+            Self::PolyPressure { channel, .. } => channel,
+            Self::ControlChange { channel, .. } => channel,
+            Self::ProgramChange { channel, .. } => channel,
+            Self::ChannelPressure { channel, .. } => channel,
+            Self::PitchBend { channel, .. } => channel
+        }
+    }
+}
+
+/// Parser for a live MIDI byte stream, e.g. a raw MIDI device. Handles
+/// running status, skips system exclusive and system common messages and
+/// passes over real time bytes which may appear anywhere in the stream.
+#[derive(Default)]
+pub struct MidiStreamParser {
+    running_status: u8,
+    data: [u8; 2],
+    data_count: usize,
+    in_sysex: bool
+}
+
+impl MidiStreamParser {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one byte, returns a message once it is complete.
+    pub fn parse(&mut self, byte: u8) -> Option<MidiMessage> {
+
+        if byte >= 0xF8 {
+            // real time messages do not affect running status
+            return None;
+        }
+
+        if byte & 0x80 != 0 {
+            self.data_count = 0;
+            self.in_sysex = byte == 0xF0;
+
+            if byte >= STATUS_SYSTEM {
+                // system common messages cancel running status
+                self.running_status = 0;
+            } else {
+                self.running_status = byte;
+            }
+            return None;
+        }
+
+        if self.in_sysex || self.running_status == 0 {
+            return None;
+        }
+
+        let len = MidiMessage::data_length(self.running_status)?;
+
+        self.data[self.data_count] = byte;
+        self.data_count += 1;
+
+        if self.data_count < len {
+            return None;
+        }
+
+        self.data_count = 0;
+
+        MidiMessage::from_bytes(self.running_status, &self.data[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut MidiStreamParser, bytes: &[u8]) -> Vec<MidiMessage> {
+        bytes.iter().filter_map(|byte| parser.parse(*byte)).collect()
+    }
+
+    fn note_on(pitch: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 0, pitch, velocity }
+    }
+
+    fn note_off(pitch: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel: 0, pitch, velocity }
+    }
+
+    #[test]
+    fn running_status() {
+        let mut parser = MidiStreamParser::new();
+
+        let messages = parse_all(&mut parser, &[0x90, 60, 100, 62, 90, 60, 0, 0x81, 62, 64, 64, 10]);
+
+        assert_eq!(messages, vec![
+            note_on(60, 100),
+            note_on(62, 90),
+            note_off(60, 0), // note on with zero velocity
+            MidiMessage::NoteOff { channel: 1, pitch: 62, velocity: 64 },
+            MidiMessage::NoteOff { channel: 1, pitch: 64, velocity: 10 }
+        ]);
+    }
+
+    #[test]
+    fn real_time_inside_message() {
+        let mut parser = MidiStreamParser::new();
+
+        let messages = parse_all(&mut parser, &[0x90, 0xF8, 60, 0xF8, 100, 0xFE, 61, 0xF8, 100]);
+
+        assert_eq!(messages, vec![note_on(60, 100), note_on(61, 100)]);
+    }
+
+    #[test]
+    fn sysex_terminated_by_status() {
+        let mut parser = MidiStreamParser::new();
+
+        // the note on ends an unterminated sysex
+        let messages = parse_all(&mut parser, &[0x90, 60, 100, 0xF0, 0x7E, 60, 100, 0x80, 60, 0]);
+        assert_eq!(messages, vec![note_on(60, 100), note_off(60, 0)]);
+
+        // a terminated sysex cancels running status
+        let messages = parse_all(&mut parser, &[0xF0, 0x01, 0x02, 0xF7, 60, 0, 0xB0, 7, 127]);
+        assert_eq!(messages, vec![MidiMessage::ControlChange { channel: 0, controller: 7, value: 127 }]);
+    }
+
+    #[test]
+    fn data_without_status() {
+        let mut parser = MidiStreamParser::new();
+
+        assert_eq!(parse_all(&mut parser, &[60, 100, 0x40]), vec![]);
+        assert_eq!(parse_all(&mut parser, &[0xC2, 5, 6]), vec![
+            MidiMessage::ProgramChange { channel: 2, program: 5 },
+            MidiMessage::ProgramChange { channel: 2, program: 6 }
+        ]);
+    }
+
+    #[test]
+    fn from_bytes() {
+        assert_eq!(MidiMessage::from_bytes(0xE3, &[0x00, 0x40]), Some(MidiMessage::PitchBend { channel: 3, value: PITCH_BEND_CENTER }));
+        assert_eq!(MidiMessage::from_bytes(0x90, &[60]), None); // too short
+        assert_eq!(MidiMessage::from_bytes(0xF2, &[0, 0]), None); // system message
+    }
+}
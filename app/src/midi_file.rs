@@ -0,0 +1,433 @@
+//!
+//! Standard MIDI File reader
+//!
+
+use log::{*};
+use std::fs;
+
+use crate::{error::Error, midi::MidiMessage};
+
+const DEFAULT_TEMPO: u32 = 500000; // microseconds per quarter note (120 bpm)
+
+const META_EVENT: u8 = 0xFF;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_SET_TEMPO: u8 = 0x51;
+const SYSEX_EVENT: u8 = 0xF0;
+const SYSEX_ESCAPE: u8 = 0xF7;
+
+const SMPTE_FRAME_RATES: [f64; 4] = [24.0, 25.0, 29.0, 30.0]; // 29 is 29.97 drop frame
+
+#[derive(Clone, Debug)]
+pub struct MidiFileEvent {
+    pub tick: u64,
+    pub time: f64, // seconds from start of file
+    pub message: MidiMessage
+}
+
+#[derive(Clone, Debug)]
+pub struct MidiFile {
+    pub format: u16,
+    pub num_tracks: u16,
+    pub events: Vec<MidiFileEvent>,
+    pub duration: f64 // seconds, including trailing meta events
+}
+
+enum Timebase {
+    TicksPerQuarter(u32),
+    Smpte(f64) // seconds per tick
+}
+
+struct TempoChange {
+    tick: u64,
+    tempo: u32
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0
+        }
+    }
+
+    fn is_eof(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        if self.pos >= self.data.len() {
+            return Err(Error::from("unexpected end of midi file"));
+        }
+        let value = self.data[self.pos];
+        self.pos += 1;
+        Ok(value)
+    }
+
+    fn peek_u8(&self) -> Result<u8, Error> {
+        if self.pos >= self.data.len() {
+            return Err(Error::from("unexpected end of midi file"));
+        }
+        Ok(self.data[self.pos])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        let hi = self.read_u8()? as u16;
+        let lo = self.read_u8()? as u16;
+        Ok((hi << 8) | lo)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let hi = self.read_u16()? as u32;
+        let lo = self.read_u16()? as u32;
+        Ok((hi << 16) | lo)
+    }
+
+    fn read_var_len(&mut self) -> Result<u32, Error> {
+        let mut value: u32 = 0;
+        for _ in 0..4 {
+            let b = self.read_u8()?;
+            value = (value << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::from("invalid variable length quantity in midi file"))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos + len > self.data.len() {
+            return Err(Error::from("unexpected end of midi file"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+impl MidiFile {
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        trace!("load midi file: {}", path);
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::from(format!("failed to read midi file '{}'", path)));
+            }
+        };
+
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+
+        let mut reader = Reader::new(data);
+
+        if reader.read_bytes(4)? != b"MThd" {
+            return Err(Error::from("not a standard midi file"));
+        }
+
+        let header_len = reader.read_u32()? as usize;
+        if header_len < 6 {
+            return Err(Error::from("invalid midi file header"));
+        }
+
+        let format = reader.read_u16()?;
+        let num_tracks = reader.read_u16()?;
+        let division = reader.read_u16()?;
+        reader.read_bytes(header_len - 6)?;
+
+        if format > 1 {
+            return Err(Error::from("unsupported midi file format"));
+        }
+
+        let timebase = if division & 0x8000 != 0 {
+            // widen before negating, the high byte may be -128
+            let frames_per_second = -(((division >> 8) as i8) as f64);
+            let ticks_per_frame = (division & 0xFF) as f64;
+            if frames_per_second <= 0.0 || ticks_per_frame <= 0.0 || !SMPTE_FRAME_RATES.contains(&frames_per_second) {
+                return Err(Error::from("invalid midi file time division"));
+            }
+            Timebase::Smpte(1.0 / (frames_per_second * ticks_per_frame))
+        } else {
+            if division == 0 {
+                return Err(Error::from("invalid midi file time division"));
+            }
+            Timebase::TicksPerQuarter(division as u32)
+        };
+
+        let mut raw_events = Vec::<(u64, MidiMessage)>::new();
+        let mut tempo_changes = Vec::<TempoChange>::new();
+        let mut last_tick: u64 = 0;
+
+        let mut track_index = 0;
+        while track_index < num_tracks && !reader.is_eof() {
+
+            let chunk_id = reader.read_bytes(4)?;
+            let chunk_len = reader.read_u32()? as usize;
+            let chunk_data = reader.read_bytes(chunk_len)?;
+
+            if chunk_id != b"MTrk" {
+                continue; // skip unknown chunks
+            }
+
+            let track_end_tick = Self::parse_track(chunk_data, &mut raw_events, &mut tempo_changes)?;
+            last_tick = last_tick.max(track_end_tick);
+
+            track_index += 1;
+        }
+
+        // stable sort keeps the order of simultaneous events within a track
+        raw_events.sort_by_key(|e| e.0);
+        tempo_changes.sort_by_key(|t| t.tick);
+
+        let events = raw_events.into_iter().map(|(tick, message)| {
+            MidiFileEvent {
+                tick,
+                time: Self::tick_to_seconds(tick, &timebase, &tempo_changes),
+                message
+            }
+        }).collect::<Vec<MidiFileEvent>>();
+
+        let duration = Self::tick_to_seconds(last_tick, &timebase, &tempo_changes);
+
+        trace!("midi file: format {}, {} tracks, {} events, {:.3}s", format, num_tracks, events.len(), duration);
+
+        Ok(Self {
+            format,
+            num_tracks,
+            events,
+            duration
+        })
+    }
+
+    fn parse_track(data: &[u8], events: &mut Vec<(u64, MidiMessage)>, tempo_changes: &mut Vec<TempoChange>) -> Result<u64, Error> {
+
+        let mut reader = Reader::new(data);
+        let mut tick: u64 = 0;
+        let mut running_status: u8 = 0;
+
+        while !reader.is_eof() {
+
+            tick += reader.read_var_len()? as u64;
+
+            let status = if reader.peek_u8()? & 0x80 != 0 {
+                reader.read_u8()?
+            } else {
+                if running_status == 0 {
+                    return Err(Error::from("midi data without status byte"));
+                }
+                running_status
+            };
+
+            if status == META_EVENT {
+                let meta_type = reader.read_u8()?;
+                let len = reader.read_var_len()? as usize;
+                let meta_data = reader.read_bytes(len)?;
+
+                if meta_type == META_SET_TEMPO && len == 3 {
+                    let tempo = ((meta_data[0] as u32) << 16) | ((meta_data[1] as u32) << 8) | (meta_data[2] as u32);
+                    tempo_changes.push(TempoChange { tick, tempo });
+                } else if meta_type == META_END_OF_TRACK {
+                    break;
+                }
+            } else if status == SYSEX_EVENT || status == SYSEX_ESCAPE {
+                let len = reader.read_var_len()? as usize;
+                reader.read_bytes(len)?;
+                running_status = 0;
+            } else {
+                let len = match MidiMessage::data_length(status) {
+                    Some(len) => len,
+                    None => {
+                        return Err(Error::from("invalid midi status byte"));
+                    }
+                };
+
+                let message_data = reader.read_bytes(len)?;
+                running_status = status;
+
+                match MidiMessage::from_bytes(status, message_data) {
+                    Some(message) => {
+                        events.push((tick, message));
+                    },
+                    None => {}
+                };
+            }
+        }
+
+        Ok(tick)
+    }
+
+    fn tick_to_seconds(tick: u64, timebase: &Timebase, tempo_changes: &[TempoChange]) -> f64 {
+        match timebase {
+            Timebase::Smpte(seconds_per_tick) => {
+                tick as f64 * seconds_per_tick
+            },
+            Timebase::TicksPerQuarter(ticks_per_quarter) => {
+                let ticks_per_quarter = *ticks_per_quarter as f64;
+
+                let mut seconds = 0.0;
+                let mut last_tick: u64 = 0;
+                let mut tempo = DEFAULT_TEMPO;
+
+                for change in tempo_changes {
+                    if change.tick >= tick {
+                        break;
+                    }
+                    seconds += (change.tick - last_tick) as f64 * tempo as f64 / (ticks_per_quarter * 1000000.0);
+                    last_tick = change.tick;
+                    tempo = change.tempo;
+                }
+
+                seconds + (tick - last_tick) as f64 * tempo as f64 / (ticks_per_quarter * 1000000.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const END_OF_TRACK: [u8; 4] = [0x00, META_EVENT, META_END_OF_TRACK, 0x00];
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn header(format: u16, num_tracks: u16, division: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&format.to_be_bytes());
+        data.extend_from_slice(&num_tracks.to_be_bytes());
+        data.extend_from_slice(&division.to_be_bytes());
+        chunk(b"MThd", &data)
+    }
+
+    fn midi_file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = header(format, tracks.len() as u16, division);
+        for track in tracks {
+            bytes.extend(chunk(b"MTrk", track));
+        }
+        bytes
+    }
+
+    fn note_on(pitch: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 0, pitch, velocity: 100 }
+    }
+
+    fn note_off(pitch: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel: 0, pitch, velocity }
+    }
+
+    fn ticks_and_messages(file: &MidiFile) -> Vec<(u64, MidiMessage)> {
+        file.events.iter().map(|e| (e.tick, e.message)).collect()
+    }
+
+    #[test]
+    fn format_0() {
+        let track = [&[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 64][..], &END_OF_TRACK].concat();
+        let file = MidiFile::parse(&midi_file(0, 96, &[&track])).unwrap();
+
+        assert_eq!(file.format, 0);
+        assert_eq!(file.num_tracks, 1);
+        assert_eq!(ticks_and_messages(&file), vec![(0, note_on(60)), (96, note_off(60, 64))]);
+        assert_eq!(file.events[1].time, 0.5); // one quarter note at 120 bpm
+        assert_eq!(file.duration, 0.5);
+    }
+
+    #[test]
+    fn format_1_with_tempo_change() {
+        // 120 bpm, then 240 bpm after one quarter note
+        let tempo_track = [
+            &[0x00, META_EVENT, META_SET_TEMPO, 0x03, 0x07, 0xA1, 0x20][..],
+            &[0x60, META_EVENT, META_SET_TEMPO, 0x03, 0x03, 0xD0, 0x90],
+            &END_OF_TRACK
+        ].concat();
+        let note_track = [&[0x00, 0x90, 60, 100, 0x81, 0x40, 0x80, 60, 0][..], &END_OF_TRACK].concat();
+
+        // unknown chunks between tracks are skipped
+        let mut data = header(1, 2, 96);
+        data.extend(chunk(b"MTrk", &tempo_track));
+        data.extend(chunk(b"XFIH", &[1, 2, 3]));
+        data.extend(chunk(b"MTrk", &note_track));
+
+        let file = MidiFile::parse(&data).unwrap();
+
+        assert_eq!(ticks_and_messages(&file), vec![(0, note_on(60)), (192, note_off(60, 0))]);
+        assert_eq!(file.events[0].time, 0.0);
+        assert_eq!(file.events[1].time, 0.75);
+        assert_eq!(file.duration, 0.75);
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames per second, 40 ticks per frame
+        let track = [&[0x00, 0x90, 60, 100, 0x87, 0x68, 0x80, 60, 0][..], &END_OF_TRACK].concat();
+        let file = MidiFile::parse(&midi_file(0, 0xE728, &[&track])).unwrap();
+
+        assert_eq!(file.events[1].tick, 1000);
+        assert!((file.events[1].time - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn running_status() {
+        let track = [
+            &[0x00, 0x90, 60, 100][..],
+            &[0x10, 62, 100],
+            &[0x10, 60, 0], // note on with zero velocity
+            &END_OF_TRACK
+        ].concat();
+        let file = MidiFile::parse(&midi_file(0, 96, &[&track])).unwrap();
+
+        assert_eq!(ticks_and_messages(&file), vec![(0, note_on(60)), (16, note_on(62)), (32, note_off(60, 0))]);
+    }
+
+    #[test]
+    fn sysex_cancels_running_status() {
+        let track = [&[0x00, 0x90, 60, 100][..], &[0x00, SYSEX_EVENT, 0x02, 0x01, 0xF7], &[0x00, 62, 100]].concat();
+
+        assert!(MidiFile::parse(&midi_file(0, 96, &[&track])).is_err());
+    }
+
+    #[test]
+    fn truncated_data() {
+        let track = [&[0x00, 0x90, 60, 100][..], &END_OF_TRACK].concat();
+        let data = midi_file(0, 96, &[&track]);
+
+        assert!(MidiFile::parse(&data[..10]).is_err()); // inside the header
+        assert!(MidiFile::parse(&data[..data.len() - 1]).is_err()); // inside the track
+
+        // a message cut off inside its track chunk
+        let track = [0x00, 0x90, 60];
+        assert!(MidiFile::parse(&midi_file(0, 96, &[&track])).is_err());
+    }
+
+    #[test]
+    fn invalid_header() {
+        let track = END_OF_TRACK.to_vec();
+
+        let mut data = midi_file(0, 96, &[&track]);
+        data[0] = b'X';
+        assert!(MidiFile::parse(&data).is_err());
+
+        assert!(MidiFile::parse(&midi_file(2, 96, &[&track])).is_err()); // format 2
+    }
+
+    #[test]
+    fn invalid_division() {
+        let track = END_OF_TRACK.to_vec();
+
+        assert!(MidiFile::parse(&midi_file(0, 0, &[&track])).is_err());
+        assert!(MidiFile::parse(&midi_file(0, 0x8028, &[&track])).is_err()); // -128 frames per second
+        assert!(MidiFile::parse(&midi_file(0, 0xE700, &[&track])).is_err()); // no ticks per frame
+        assert!(MidiFile::parse(&midi_file(0, 0xF028, &[&track])).is_err()); // 16 frames per second
+    }
+}
@@ -0,0 +1,98 @@
+//!
+//! MIDI input
+//!
+//! Live MIDI input from a device. Backends read on their own thread and
+//! pass every parsed message, stamped with its arrival time, to the input
+//! callback.
+//!
+
+use log::{*};
+use std::time::Instant;
+
+#[cfg(target_os = "linux")]
+use crate::midi_input_alsa::AlsaMidiInput;
+use crate::{error::Error, midi::MidiMessage};
+
+#[derive(Clone, Copy, Debug)]
+pub struct MidiInputEvent {
+    pub message: MidiMessage,
+    pub time: Instant
+}
+
+/// How live MIDI input is placed within the audio blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiTimingMode {
+    /// Events play at the start of the next block, lowest latency but
+    /// jitter of up to one block.
+    Immediate,
+    /// Events are delayed by exactly one block and keep their relative
+    /// timing, sample accurate with a constant latency.
+    BlockLatency
+}
+
+impl MidiTimingMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "immediate" => Some(Self::Immediate),
+            "block" => Some(Self::BlockLatency),
+            _ => None
+        }
+    }
+}
+
+pub type MidiInputCallback = Box<dyn FnMut(&MidiInputEvent) + Send>;
+
+pub trait MidiInputBackend {
+    fn open(name: &str) -> Result<Self, Error> where Self: Sized;
+    fn start(&mut self, callback: MidiInputCallback) -> Result<(), Error>;
+    fn stop(&mut self) -> Result<(), Error>;
+    fn dispose(&mut self);
+}
+
+pub struct MidiInput {
+    backend: Box<dyn MidiInputBackend>
+}
+
+impl MidiInput {
+    /// Opens a MIDI input port, see the backend for the supported names.
+    pub fn new(name: &str) -> Result<Self, Error> {
+        trace!("new");
+
+        #[cfg(target_os = "linux")]
+        let backend: Box<dyn MidiInputBackend> = Box::new(AlsaMidiInput::open(name)?);
+
+        #[cfg(not(target_os = "linux"))]
+        let backend: Box<dyn MidiInputBackend> = {
+            let _ = name;
+            return Err(Error::from("MIDI input is not supported on this platform"));
+        };
+
+        Ok(Self {
+            backend
+        })
+    }
+
+    pub fn start<F>(&mut self, callback: F) -> Result<(), Error>
+    where
+        F: 'static + FnMut(&MidiInputEvent) + Send
+    {
+        self.backend.start(Box::new(callback))
+    }
+
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.backend.stop()
+    }
+
+    pub fn dispose(&mut self) {
+        self.backend.dispose();
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn list_ports() {
+        AlsaMidiInput::list_ports();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn list_ports() {
+    }
+}
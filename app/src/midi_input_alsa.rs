@@ -0,0 +1,390 @@
+//!
+//! ALSA MIDI input backend
+//!
+//! Creates a sequencer port named "Keystone:Input". The port name passed
+//! to `open` selects the source:
+//!
+//! - empty: no source, connect the port externally (e.g. `aconnect`)
+//! - `hw:...`: raw MIDI device, read without the sequencer
+//! - `client:port`: sequencer address, e.g. `20:0`
+//! - anything else: first readable sequencer port whose client or port
+//!   name contains the given text
+//!
+
+use log::{*};
+use std::{ffi::CString, io::Read, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc}, thread::JoinHandle, time::Instant};
+
+use alsa::{poll::Descriptors, rawmidi::Rawmidi, seq::{Addr, ClientIter, EvCtrl, EvNote, EventType, PortCap, PortInfo, PortIter, PortSubscribe, PortType, Seq}, Direction};
+
+use crate::{error::Error, midi::{MidiMessage, MidiStreamParser, PITCH_BEND_CENTER}, midi_input::{MidiInputBackend, MidiInputCallback, MidiInputEvent}};
+
+const CLIENT_NAME: &str = "Keystone";
+const PORT_NAME: &str = "Input";
+const POLL_TIMEOUT_MS: i32 = 100; // how often the input thread checks for stop
+const RAW_MIDI_PREFIX: &str = "hw:";
+
+pub struct AlsaMidiInput {
+    name: String,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl AlsaMidiInput {
+
+    /// Prints the readable sequencer ports.
+    pub fn list_ports() {
+        trace!("list ports");
+
+        let seq = match Seq::open(None, Some(Direction::Capture), true) {
+            Ok(seq) => seq,
+            Err(e) => {
+                error!("failed to open ALSA sequencer: {}", e);
+                return;
+            }
+        };
+
+        for client in ClientIter::new(&seq) {
+            for port in PortIter::new(&seq, client.get_client()) {
+                if !port.get_capability().contains(PortCap::READ | PortCap::SUBS_READ) {
+                    continue;
+                }
+                println!("MIDI input: {}:{} - {} - {}", port.get_client(), port.get_port(), client.get_name().unwrap_or(""), port.get_name().unwrap_or(""));
+            }
+        }
+    }
+
+    fn run_seq(name: String, running: Arc<AtomicBool>, mut callback: MidiInputCallback, ready: mpsc::Sender<Result<(), Error>>) {
+        let seq = match Self::open_seq(&name) {
+            Ok(seq) => {
+                let _ = ready.send(Ok(()));
+                seq
+            },
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return;
+            }
+        };
+
+        trace!("sequencer input thread started");
+
+        let mut fds = match (&seq, Some(Direction::Capture)).get() {
+            Ok(fds) => fds,
+            Err(_) => {
+                error!("failed to get ALSA sequencer poll descriptors");
+                return;
+            }
+        };
+
+        let mut input = seq.input();
+
+        while running.load(Ordering::Acquire) {
+            match alsa::poll::poll(&mut fds, POLL_TIMEOUT_MS) {
+                Ok(0) => {
+                    continue;
+                },
+                Ok(_) => {},
+                Err(_) => {
+                    break;
+                }
+            };
+
+            // drain everything that arrived
+            while input.event_input_pending(true).unwrap_or(0) > 0 {
+                let event = match input.event_input() {
+                    Ok(event) => event,
+                    Err(_) => {
+                        break;
+                    }
+                };
+
+                let time = Instant::now();
+
+                match Self::message_from_seq_event(&event) {
+                    Some(message) => {
+                        callback(&MidiInputEvent {
+                            message,
+                            time
+                        });
+                    },
+                    None => {}
+                };
+            }
+        }
+
+        trace!("sequencer input thread stopped");
+    }
+
+    fn open_seq(name: &str) -> Result<Seq, Error> {
+        let seq = match Seq::open(None, Some(Direction::Capture), true) {
+            Ok(seq) => seq,
+            Err(e) => {
+                return Err(Error::from(format!("failed to open ALSA sequencer: {}", e)));
+            }
+        };
+
+        let _ = seq.set_client_name(&CString::new(CLIENT_NAME).unwrap());
+
+        let mut port_info = match PortInfo::empty() {
+            Ok(port_info) => port_info,
+            Err(_) => {
+                return Err(Error::from("failed to allocate ALSA port info"));
+            }
+        };
+        port_info.set_capability(PortCap::WRITE | PortCap::SUBS_WRITE);
+        port_info.set_type(PortType::MIDI_GENERIC | PortType::APPLICATION);
+        port_info.set_name(&CString::new(PORT_NAME).unwrap());
+
+        if seq.create_port(&port_info).is_err() {
+            return Err(Error::from("failed to create ALSA sequencer port"));
+        }
+
+        let client = match seq.client_id() {
+            Ok(client) => client,
+            Err(_) => {
+                return Err(Error::from("failed to get ALSA sequencer client id"));
+            }
+        };
+
+        let dest = Addr {
+            client,
+            port: port_info.get_port()
+        };
+
+        debug!("MIDI input port {}:{}", dest.client, dest.port);
+
+        if name.is_empty() {
+            return Ok(seq);
+        }
+
+        let sender = Self::find_source(&seq, name)?;
+
+        let subscription = match PortSubscribe::empty() {
+            Ok(subscription) => subscription,
+            Err(_) => {
+                return Err(Error::from("failed to allocate ALSA port subscription"));
+            }
+        };
+        subscription.set_sender(sender);
+        subscription.set_dest(dest);
+
+        if seq.subscribe_port(&subscription).is_err() {
+            return Err(Error::from(format!("failed to connect MIDI input '{}'", name)));
+        }
+
+        debug!("MIDI input connected to {}:{}", sender.client, sender.port);
+
+        Ok(seq)
+    }
+
+    fn find_source(seq: &Seq, name: &str) -> Result<Addr, Error> {
+        // numeric address
+        match name.split_once(':') {
+            Some((client, port)) => {
+                match (client.trim().parse::<i32>(), port.trim().parse::<i32>()) {
+                    (Ok(client), Ok(port)) => {
+                        return Ok(Addr { client, port });
+                    },
+                    _ => {}
+                }
+            },
+            None => {}
+        };
+
+        let pattern = name.to_lowercase();
+
+        for client in ClientIter::new(seq) {
+            let client_name = client.get_name().unwrap_or("").to_lowercase();
+            for port in PortIter::new(seq, client.get_client()) {
+                if !port.get_capability().contains(PortCap::READ | PortCap::SUBS_READ) {
+                    continue;
+                }
+                let port_name = port.get_name().unwrap_or("").to_lowercase();
+                if client_name.contains(&pattern) || port_name.contains(&pattern) {
+                    return Ok(port.addr());
+                }
+            }
+        }
+
+        Err(Error::from(format!("MIDI input '{}' not found", name)))
+    }
+
+    fn message_from_seq_event(event: &alsa::seq::Event) -> Option<MidiMessage> {
+        let message = match event.get_type() {
+            EventType::Noteon => {
+                let note: EvNote = event.get_data()?;
+                if note.velocity == 0 {
+                    MidiMessage::NoteOff { channel: note.channel & 0x0F, pitch: note.note & 0x7F, velocity: 0 }
+                } else {
+                    MidiMessage::NoteOn { channel: note.channel & 0x0F, pitch: note.note & 0x7F, velocity: note.velocity & 0x7F }
+                }
+            },
+            EventType::Noteoff => {
+                let note: EvNote = event.get_data()?;
+                MidiMessage::NoteOff { channel: note.channel & 0x0F, pitch: note.note & 0x7F, velocity: note.velocity & 0x7F }
+            },
+            EventType::Keypress => {
+                let note: EvNote = event.get_data()?;
+                MidiMessage::PolyPressure { channel: note.channel & 0x0F, pitch: note.note & 0x7F, pressure: note.velocity & 0x7F }
+            },
+            EventType::Controller => {
+                let ctrl: EvCtrl = event.get_data()?;
+                MidiMessage::ControlChange { channel: ctrl.channel & 0x0F, controller: (ctrl.param & 0x7F) as u8, value: (ctrl.value & 0x7F) as u8 }
+            },
+            EventType::Pgmchange => {
+                let ctrl: EvCtrl = event.get_data()?;
+                MidiMessage::ProgramChange { channel: ctrl.channel & 0x0F, program: (ctrl.value & 0x7F) as u8 }
+            },
+            EventType::Chanpress => {
+                let ctrl: EvCtrl = event.get_data()?;
+                MidiMessage::ChannelPressure { channel: ctrl.channel & 0x0F, pressure: (ctrl.value & 0x7F) as u8 }
+            },
+            EventType::Pitchbend => {
+                // the sequencer reports pitch bend centered around zero
+                let ctrl: EvCtrl = event.get_data()?;
+                let value = (ctrl.value + PITCH_BEND_CENTER as i32).clamp(0, 0x3FFF) as u16;
+                MidiMessage::PitchBend { channel: ctrl.channel & 0x0F, value }
+            },
+            _ => {
+                return None;
+            }
+        };
+
+        Some(message)
+    }
+
+    fn run_raw(name: String, running: Arc<AtomicBool>, mut callback: MidiInputCallback, ready: mpsc::Sender<Result<(), Error>>) {
+        let rawmidi = match Rawmidi::new(&name, Direction::Capture, true) {
+            Ok(rawmidi) => {
+                let _ = ready.send(Ok(()));
+                rawmidi
+            },
+            Err(e) => {
+                let _ = ready.send(Err(Error::from(format!("failed to open raw MIDI device '{}': {}", name, e))));
+                return;
+            }
+        };
+
+        trace!("raw MIDI input thread started");
+
+        let mut fds = match rawmidi.get() {
+            Ok(fds) => fds,
+            Err(_) => {
+                error!("failed to get raw MIDI poll descriptors");
+                return;
+            }
+        };
+
+        let mut parser = MidiStreamParser::new();
+        let mut buffer = [0u8; 256];
+
+        while running.load(Ordering::Acquire) {
+            match alsa::poll::poll(&mut fds, POLL_TIMEOUT_MS) {
+                Ok(0) => {
+                    continue;
+                },
+                Ok(_) => {},
+                Err(_) => {
+                    break;
+                }
+            };
+
+            let count = match rawmidi.io().read(&mut buffer) {
+                Ok(count) => count,
+                Err(_) => {
+                    // nothing available on a non blocking read
+                    continue;
+                }
+            };
+
+            let time = Instant::now();
+
+            for byte in &buffer[..count] {
+                match parser.parse(*byte) {
+                    Some(message) => {
+                        callback(&MidiInputEvent {
+                            message,
+                            time
+                        });
+                    },
+                    None => {}
+                };
+            }
+        }
+
+        trace!("raw MIDI input thread stopped");
+    }
+}
+
+impl MidiInputBackend for AlsaMidiInput {
+    fn open(name: &str) -> Result<Self, Error> {
+        trace!("open '{}'", name);
+
+        Ok(Self {
+            name: name.to_string(),
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None
+        })
+    }
+
+    fn start(&mut self, callback: MidiInputCallback) -> Result<(), Error> {
+        if self.thread.is_some() {
+            return Err(Error::from("MIDI input already started"));
+        }
+
+        self.running.store(true, Ordering::Release);
+
+        let running = self.running.clone();
+        let name = self.name.clone();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+
+        // the device is opened on the input thread, which reports back whether that worked
+        let thread = match std::thread::Builder::new()
+            .name("midi-input".to_string())
+            .spawn(move || {
+                if name.starts_with(RAW_MIDI_PREFIX) {
+                    Self::run_raw(name, running, callback, ready_sender);
+                } else {
+                    Self::run_seq(name, running, callback, ready_sender);
+                }
+            }) {
+            Ok(thread) => thread,
+            Err(_) => {
+                self.running.store(false, Ordering::Release);
+                return Err(Error::from("failed to start MIDI input thread"));
+            }
+        };
+
+        let result = match ready_receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::from("MIDI input thread failed"))
+        };
+
+        self.thread = Some(thread);
+
+        if result.is_err() {
+            let _ = self.stop();
+        }
+
+        result
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        trace!("stop");
+
+        self.running.store(false, Ordering::Release);
+
+        match self.thread.take() {
+            Some(thread) => {
+                thread.join().map_err(|_| Error::from("MIDI input thread panicked"))?;
+            },
+            None => {}
+        };
+
+        Ok(())
+    }
+
+    fn dispose(&mut self) {
+        trace!("dispose");
+        let _ = self.stop();
+    }
+}
@@ -0,0 +1,157 @@
+//!
+//! MIDI controller mapping
+//!
+//! VST3 has no controller events, plugins assign MIDI controllers, channel
+//! pressure and pitch bend to parameters through `IMidiMapping`. The
+//! assignments are queried on the control thread and looked up on the
+//! audio thread without allocating. Program changes select the program
+//! list parameter of the root unit, the parameter flagged `kIsProgramChange`.
+//!
+
+use log::{*};
+use vst3_sys::{base::kResultOk, vst::{kRootUnitId, IMidiMapping, ParameterFlags}};
+
+use crate::{edit_controller::EditController, host::Host, midi::{MidiMessage, CTRL_COUNT, MIDI_CHANNEL_COUNT}, parameters::ParamID};
+
+pub fn register_interfaces(host: &Host) {
+    host.register_interface_support::<dyn IMidiMapping>();
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ProgramChangeParameter {
+    id: ParamID,
+    step_count: i32 // last program
+}
+
+#[derive(Clone, Default)]
+pub struct MidiMapping {
+    bus_count: usize,
+    assignments: Vec<Option<ParamID>>, // per bus, channel and controller number
+    program_change: Option<ProgramChangeParameter>
+}
+
+impl MidiMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the controller for every controller number on every event input
+    /// bus and looks up its program change parameter.
+    pub fn query(controller: &EditController, bus_count: usize) -> Self {
+        let program_change = Self::query_program_change(controller);
+
+        let midi_mapping = match controller.query_midi_mapping_intf() {
+            Ok(intf) => intf,
+            Err(_) => {
+                trace!("no MIDI controller mapping");
+                return Self {
+                    program_change,
+                    ..Self::default()
+                };
+            }
+        };
+
+        let mut assignments = vec![None; bus_count * MIDI_CHANNEL_COUNT * CTRL_COUNT];
+        let mut count = 0;
+
+        for bus_index in 0..bus_count {
+            for channel in 0..MIDI_CHANNEL_COUNT {
+                for ctrl_number in 0..CTRL_COUNT {
+                    let mut id: ParamID = 0;
+                    let result = unsafe { midi_mapping.get_midi_controller_assignment(bus_index as i32, channel as i16, ctrl_number as i16, &mut id) };
+                    if result == kResultOk {
+                        assignments[Self::index(bus_index, channel, ctrl_number)] = Some(id);
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        debug!("{} MIDI controllers mapped to parameters", count);
+
+        Self {
+            bus_count,
+            assignments,
+            program_change
+        }
+    }
+
+    /// Program change parameter of the root unit, or of any unit if the
+    /// root unit has none.
+    fn query_program_change(controller: &EditController) -> Option<ProgramChangeParameter> {
+        let mut program_change = None;
+
+        for index in 0..controller.get_parameter_count() {
+            let info = match controller.get_parameter_info(index) {
+                Ok(info) => info,
+                Err(_) => {
+                    continue;
+                }
+            };
+
+            if info.flags & ParameterFlags::kIsProgramChange as i32 == 0 {
+                continue;
+            }
+
+            let parameter = ProgramChangeParameter {
+                id: info.id,
+                step_count: info.step_count
+            };
+
+            if info.unit_id == kRootUnitId {
+                program_change = Some(parameter);
+                break;
+            }
+
+            if program_change.is_none() {
+                program_change = Some(parameter);
+            }
+        }
+
+        match program_change {
+            Some(parameter) => {
+                debug!("program changes mapped to parameter {}", parameter.id);
+            },
+            None => {}
+        };
+
+        program_change
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.program_change.is_none() && self.assignments.iter().all(|id| id.is_none())
+    }
+
+    pub fn get_parameter(&self, bus_index: usize, channel: usize, ctrl_number: usize) -> Option<ParamID> {
+        if bus_index >= self.bus_count || channel >= MIDI_CHANNEL_COUNT || ctrl_number >= CTRL_COUNT {
+            return None;
+        }
+        self.assignments[Self::index(bus_index, channel, ctrl_number)]
+    }
+
+    /// Parameter and normalized value a controller or program change
+    /// message changes, if any.
+    pub fn map(&self, bus_index: usize, message: &MidiMessage) -> Option<(ParamID, f64)> {
+        match *message {
+            MidiMessage::ProgramChange { program, .. } => {
+                // the program list of the root unit plays on every channel
+                let parameter = self.program_change?;
+                let value = if parameter.step_count > 0 {
+                    (program as i32).min(parameter.step_count) as f64 / parameter.step_count as f64
+                } else {
+                    program as f64 / 127.0
+                };
+                return Some((parameter.id, value));
+            },
+            _ => {}
+        };
+
+        let (ctrl_number, value) = message.controller_value()?;
+        let id = self.get_parameter(bus_index, message.channel() as usize, ctrl_number as usize)?;
+        Some((id, value))
+    }
+
+    fn index(bus_index: usize, channel: usize, ctrl_number: usize) -> usize {
+        (bus_index * MIDI_CHANNEL_COUNT + channel) * CTRL_COUNT + ctrl_number
+    }
+}
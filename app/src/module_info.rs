@@ -0,0 +1,278 @@
+//!
+//! Module info
+//!
+//! VST3 SDK 3.7+ bundles describe their factory and classes in
+//! `Contents/Resources/moduleinfo.json`. Reading it lets the registry
+//! list a plugin without loading its library. The file uses a relaxed
+//! JSON syntax (comments, trailing commas), so it is parsed as JSON5.
+//!
+
+use std::{fs, path::{Path, PathBuf}};
+
+use log::{*};
+use serde::Deserialize;
+use vst3_com::sys::GUID;
+
+use crate::{bundle::get_bundle_path, error::Error, plugin::{get_identifier_from_path, ClassInfo, FactoryInfo, PluginInfo}, utils::{get_file_time, slashify_path, GuidStringify}};
+
+const MODULE_INFO_FILENAME: &str = "moduleinfo.json";
+
+const FACTORY_FLAG_CLASSES_DISCARDABLE: i32 = 1 << 0;
+const FACTORY_FLAG_LICENSE_CHECK: i32 = 1 << 1;
+const FACTORY_FLAG_COMPONENT_NON_DISCARDABLE: i32 = 1 << 3;
+const FACTORY_FLAG_UNICODE: i32 = 1 << 4;
+
+#[derive(Deserialize, Default)]
+struct ModuleFactoryFlags {
+    #[serde(rename = "Classes Discardable", default)]
+    classes_discardable: bool,
+    #[serde(rename = "License Check", default)]
+    license_check: bool,
+    #[serde(rename = "Component Non Discardable", default)]
+    component_non_discardable: bool,
+    #[serde(rename = "Unicode", default)]
+    unicode: bool
+}
+
+#[derive(Deserialize, Default)]
+struct ModuleFactoryInfo {
+    #[serde(rename = "Vendor", default)]
+    vendor: String,
+    #[serde(rename = "URL", default)]
+    url: String,
+    #[serde(rename = "E-Mail", default)]
+    email: String,
+    #[serde(rename = "Flags", default)]
+    flags: ModuleFactoryFlags
+}
+
+#[derive(Deserialize)]
+struct ModuleClassInfo {
+    #[serde(rename = "CID")]
+    cid: String,
+    #[serde(rename = "Category", default)]
+    category: String,
+    #[serde(rename = "Name", default)]
+    name: String,
+    #[serde(rename = "Vendor", default)]
+    vendor: String,
+    #[serde(rename = "Version", default)]
+    version: String,
+    #[serde(rename = "SDKVersion", default)]
+    sdk_version: String,
+    #[serde(rename = "Sub Categories", default)]
+    sub_categories: Vec<String>,
+    #[serde(rename = "Class Flags", default)]
+    class_flags: u32,
+    #[serde(rename = "Cardinality", default)]
+    cardinality: i32
+}
+
+#[derive(Deserialize)]
+struct ModuleInfoFile {
+    #[serde(rename = "Name", default)]
+    name: String,
+    #[serde(rename = "Version", default)]
+    version: String,
+    #[serde(rename = "Factory Info", default)]
+    factory_info: ModuleFactoryInfo,
+    #[serde(rename = "Classes", default)]
+    classes: Vec<ModuleClassInfo>
+}
+
+pub struct ModuleInfo {
+}
+
+impl ModuleInfo {
+
+    /// Returns the moduleinfo.json of the bundle containing `library_path`,
+    /// or None for single-file plugins and bundles without one.
+    pub fn find(library_path: &str) -> Option<PathBuf> {
+        let bundle_path = PathBuf::from(get_bundle_path(library_path));
+        if bundle_path == Path::new(&slashify_path(library_path)) {
+            return None;
+        }
+
+        // SDK 3.7.5+ moved the file from Contents to Contents/Resources
+        let candidates = [
+            bundle_path.join("Contents").join("Resources").join(MODULE_INFO_FILENAME),
+            bundle_path.join("Contents").join(MODULE_INFO_FILENAME)
+        ];
+
+        candidates.into_iter().find(|p| p.is_file())
+    }
+
+    /// Builds the plugin info for `library_path` from the bundle moduleinfo.json.
+    /// Returns Ok(None) if the bundle does not provide one.
+    pub fn load(library_path: &str) -> Result<Option<PluginInfo>, Error> {
+
+        let module_info_path = match Self::find(library_path) {
+            Some(path) => path,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        trace!("load module info: {}", module_info_path.to_string_lossy());
+
+        let data = match fs::read_to_string(&module_info_path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::from("failed to read module info"));
+            }
+        };
+
+        Self::parse(library_path, &data).map(Some)
+    }
+
+    pub fn parse(library_path: &str, data: &str) -> Result<PluginInfo, Error> {
+
+        let module_info: ModuleInfoFile = match json5::from_str(data) {
+            Ok(module_info) => module_info,
+            Err(e) => {
+                return Err(Error::from(format!("invalid module info: {}", e)));
+            }
+        };
+
+        trace!("module info: name: \"{}\", version: \"{}\"", module_info.name, module_info.version);
+
+        let factory = FactoryInfo {
+            vendor: module_info.factory_info.vendor,
+            url: module_info.factory_info.url,
+            email: module_info.factory_info.email,
+            flags: Self::get_factory_flags(&module_info.factory_info.flags)
+        };
+
+        let mut classes = Vec::<ClassInfo>::new();
+
+        for class in module_info.classes {
+            let cid = class.cid.replace('-', "");
+            if cid.len() != 32 || !cid.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::from(format!("invalid class id '{}' in module info", class.cid)));
+            }
+
+            let class_info = ClassInfo {
+                name: class.name,
+                category: class.category,
+                cardinality: class.cardinality,
+                cid: GUID::from_string(&cid),
+                vendor: if class.vendor.is_empty() { factory.vendor.clone() } else { class.vendor },
+                version: class.version,
+                sdk_version: class.sdk_version,
+                sub_categories: class.sub_categories,
+                class_flags: class.class_flags
+            };
+
+            trace!(" - class info: name: \"{}\", category: \"{}\", sub categories: \"{}\"", class_info.name, class_info.category, class_info.sub_categories.join("|"));
+
+            classes.push(class_info);
+        }
+
+        Ok(PluginInfo {
+            id: get_identifier_from_path(library_path),
+            path: slashify_path(library_path),
+            file_time: get_file_time(library_path),
+            blocked: false,
+            error: None,
+            factory,
+            classes
+        })
+    }
+
+    fn get_factory_flags(flags: &ModuleFactoryFlags) -> i32 {
+        let mut value = 0;
+        if flags.classes_discardable {
+            value |= FACTORY_FLAG_CLASSES_DISCARDABLE;
+        }
+        if flags.license_check {
+            value |= FACTORY_FLAG_LICENSE_CHECK;
+        }
+        if flags.component_non_discardable {
+            value |= FACTORY_FLAG_COMPONENT_NON_DISCARDABLE;
+        }
+        if flags.unicode {
+            value |= FACTORY_FLAG_UNICODE;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIBRARY_PATH: &str = "/nonexistent/Synth.vst3/Contents/x86_64-linux/Synth.so";
+
+    // relaxed JSON as written by the SDK moduleinfotool
+    const MODULE_INFO: &str = r#"{
+        // generated
+        "Name": "Synth",
+        "Version": "1.2.0",
+        "Factory Info": {
+            "Vendor": "Keystone",
+            "URL": "https://example.com",
+            "E-Mail": "info@example.com",
+            "Flags": {
+                "Unicode": true,
+                "Classes Discardable": false,
+                "Component Non Discardable": true,
+            },
+        },
+        "Classes": [
+            {
+                "CID": "0123456789ABCDEF0123456789ABCDEF",
+                "Category": "Audio Module Class",
+                "Name": "Synth",
+                "Version": "1.2.0",
+                "SDKVersion": "VST 3.7.9",
+                "Sub Categories": ["Instrument", "Synth"],
+                "Class Flags": 1,
+                "Cardinality": 2147483647,
+            },
+            {
+                "CID": "FEDCBA98-7654-3210-FEDC-BA9876543210",
+                "Category": "Component Controller Class",
+                "Name": "Synth Controller",
+                "Vendor": "Other",
+            },
+        ],
+    }"#;
+
+    #[test]
+    fn parse() {
+        let info = ModuleInfo::parse(LIBRARY_PATH, MODULE_INFO).unwrap();
+
+        assert_eq!(info.path, LIBRARY_PATH);
+        assert_eq!(info.factory.vendor, "Keystone");
+        assert_eq!(info.factory.email, "info@example.com");
+        assert_eq!(info.factory.flags, FACTORY_FLAG_UNICODE | FACTORY_FLAG_COMPONENT_NON_DISCARDABLE);
+        assert_eq!(info.classes.len(), 2);
+
+        let processor = &info.classes[0];
+        assert_eq!(processor.cid.to_string(), "0123456789ABCDEF0123456789ABCDEF");
+        assert_eq!(processor.category, "Audio Module Class");
+        assert_eq!(processor.sub_categories, vec!["Instrument", "Synth"]);
+        assert_eq!(processor.vendor, "Keystone"); // from the factory
+        assert_eq!(processor.class_flags, 1);
+        assert_eq!(processor.cardinality, i32::MAX);
+
+        let controller = &info.classes[1];
+        assert_eq!(controller.cid.to_string(), "FEDCBA9876543210FEDCBA9876543210");
+        assert_eq!(controller.vendor, "Other");
+        assert!(controller.sub_categories.is_empty());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(ModuleInfo::parse(LIBRARY_PATH, "").is_err());
+        assert!(ModuleInfo::parse(LIBRARY_PATH, &MODULE_INFO[..MODULE_INFO.len() / 2]).is_err());
+        assert!(ModuleInfo::parse(LIBRARY_PATH, r#"{ "Classes": [ { "Name": "no class id" } ] }"#).is_err());
+        assert!(ModuleInfo::parse(LIBRARY_PATH, r#"{ "Classes": "not a list" }"#).is_err());
+    }
+
+    #[test]
+    fn invalid_class_id() {
+        assert!(ModuleInfo::parse(LIBRARY_PATH, r#"{ "Classes": [ { "CID": "0123" } ] }"#).is_err());
+        assert!(ModuleInfo::parse(LIBRARY_PATH, r#"{ "Classes": [ { "CID": "0123456789ABCDEF0123456789ABCDEG" } ] }"#).is_err());
+    }
+}
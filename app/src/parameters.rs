@@ -1,16 +1,39 @@
-use std::ptr::null_mut;
+//!
+//! Parameter changes
+//!
+//! Per-block parameter queues passed to `IAudioProcessor::process`. Queues
+//! and points are preallocated, so filling them on the audio thread does
+//! not allocate as long as the limits below are not exceeded. Like the
+//! event lists they are not synchronized, the owner of the process data
+//! and the plugin within `process` take turns using them.
+//!
 
-use vst3_sys::{base::{kResultFalse, tresult}, utils::StaticVstPtr, vst::{IParamValueQueue, IParamValueQueueVTable, IParameterChanges, IParameterChangesVTable}, VST3};
+use std::{cell::{Cell, UnsafeCell}, ptr::null_mut};
 
+use vst3_sys::{base::{kInvalidArgument, kResultFalse, kResultOk, tresult}, utils::StaticVstPtr, vst::{IParamValueQueue, IParamValueQueueVTable, IParameterChanges, IParameterChangesVTable}, VST3};
+
+pub type ParamID = u32;
+
+const MAX_PARAMETER_COUNT: usize = 64;
+const MAX_POINT_COUNT: usize = 64;
+
+/// Change of a single parameter at a sample offset within a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParameterChange {
+    pub id: ParamID,
+    pub sample_offset: i32,
+    pub value: f64
+}
 
 #[VST3(implements(IParamValueQueue))]
 pub struct ParamValueQueue {
+    id: Cell<ParamID>,
+    points: UnsafeCell<Vec<(i32, f64)>>
 }
 
 impl ParamValueQueue {
     pub fn new() -> Box<Self> {
-        let instance = Self::allocate();
-        instance
+        Self::allocate(Cell::new(0), UnsafeCell::new(Vec::with_capacity(MAX_POINT_COUNT)))
     }
 
     pub fn get_static_ptr(&self) -> StaticVstPtr<dyn IParamValueQueue> {
@@ -25,39 +48,101 @@ impl ParamValueQueue {
         let ptr: StaticVstPtr<dyn IParamValueQueue> = unsafe {
             std::mem::transmute(null_ptr as *mut _)
         };
-        return ptr
+        ptr
+    }
+
+    pub fn get_id(&self) -> ParamID {
+        self.id.get()
+    }
+
+    pub fn get_points(&self) -> &[(i32, f64)] {
+        unsafe { &*self.points.get() }
+    }
+
+    pub fn get_last_point(&self) -> Option<(i32, f64)> {
+        self.get_points().last().copied()
+    }
+
+    fn reset(&self, id: ParamID) {
+        self.id.set(id);
+        // the plugin only calls back from within process, never concurrently
+        unsafe { (*self.points.get()).clear() };
+    }
+
+    /// Inserts a point keeping the offsets sorted, a point at an existing
+    /// offset replaces its value. Returns the index of the point.
+    fn insert_point(&self, sample_offset: i32, value: f64) -> Option<usize> {
+        // the plugin only calls back from within process, never concurrently
+        let points = unsafe { &mut *self.points.get() };
+
+        let index = points.partition_point(|(offset, _)| *offset < sample_offset);
+
+        if index < points.len() && points[index].0 == sample_offset {
+            points[index].1 = value;
+            return Some(index);
+        }
+
+        if points.len() >= MAX_POINT_COUNT {
+            return None;
+        }
+
+        points.insert(index, (sample_offset, value));
+
+        Some(index)
     }
 }
 
 impl IParamValueQueue for ParamValueQueue {
     unsafe fn get_parameter_id(&self) -> u32 {
-        0
+        self.get_id()
     }
 
     unsafe fn get_point_count(&self) -> i32 {
-        0
+        self.get_points().len() as i32
     }
 
-    unsafe fn get_point(&self, _index: i32, _sample_offset: *mut i32, _value: *mut f64) -> tresult {
-        kResultFalse
+    unsafe fn get_point(&self, index: i32, sample_offset: *mut i32, value: *mut f64) -> tresult {
+        let points = self.get_points();
+
+        if index < 0 || index as usize >= points.len() {
+            return kInvalidArgument;
+        }
+
+        let (point_offset, point_value) = points[index as usize];
+
+        if !sample_offset.is_null() {
+            *sample_offset = point_offset;
+        }
+        if !value.is_null() {
+            *value = point_value;
+        }
+
+        kResultOk
     }
 
-    unsafe fn add_point(&self, _sample_offset: i32, _value: f64, _index: *mut i32) -> tresult {
-        kResultFalse
+    unsafe fn add_point(&self, sample_offset: i32, value: f64, index: *mut i32) -> tresult {
+        match self.insert_point(sample_offset, value) {
+            Some(point_index) => {
+                if !index.is_null() {
+                    *index = point_index as i32;
+                }
+                kResultOk
+            },
+            None => kResultFalse
+        }
     }
 }
 
 #[VST3(implements(IParameterChanges))]
-
 pub struct ParameterChanges {
-    param_value_queue: Box<ParamValueQueue>
+    queues: [Box<ParamValueQueue>; MAX_PARAMETER_COUNT],
+    queue_count: Cell<usize>
 }
 
 impl ParameterChanges {
     pub fn new() -> Box<Self> {
-        let param_value_queue = ParamValueQueue::new();
-        let instance = Self::allocate(param_value_queue);
-        instance
+        let queues = std::array::from_fn(|_| ParamValueQueue::new());
+        Self::allocate(queues, Cell::new(0))
     }
 
     pub fn get_static_ptr(&mut self) -> StaticVstPtr<dyn IParameterChanges> {
@@ -72,29 +157,145 @@ impl ParameterChanges {
         let ptr: StaticVstPtr<dyn IParameterChanges> = unsafe {
             std::mem::transmute(null_ptr as *mut _)
         };
-        return ptr
+        ptr
+    }
+
+    /// Adds a change to the queue of its parameter. Like `SpscQueue::push`
+    /// a change that does not fit is handed back, the audio thread counts
+    /// it instead of building an error.
+    pub fn add_change(&mut self, change: &ParameterChange) -> Result<(), ParameterChange> {
+        let queue = match self.find_or_add_queue(change.id) {
+            Some((_, queue)) => queue,
+            None => {
+                return Err(*change);
+            }
+        };
+
+        match queue.insert_point(change.sample_offset, change.value) {
+            Some(_) => Ok(()),
+            None => Err(*change)
+        }
     }
 
+    pub fn get_queue_count(&self) -> usize {
+        self.queue_count.get()
+    }
+
+    pub fn get_queue(&self, index: usize) -> Option<&ParamValueQueue> {
+        if index >= self.get_queue_count() {
+            return None;
+        }
+        Some(self.queues[index].as_ref())
+    }
+
+    /// Removes all queues, called after each process call.
+    pub fn clear(&mut self) {
+        self.queue_count.set(0);
+    }
+
+    fn find_or_add_queue(&self, id: ParamID) -> Option<(usize, &ParamValueQueue)> {
+        let queue_count = self.queue_count.get();
+
+        for index in 0..queue_count {
+            if self.queues[index].get_id() == id {
+                return Some((index, self.queues[index].as_ref()));
+            }
+        }
+
+        if queue_count >= self.queues.len() {
+            return None;
+        }
+
+        self.queues[queue_count].reset(id);
+        self.queue_count.set(queue_count + 1);
+
+        Some((queue_count, self.queues[queue_count].as_ref()))
+    }
 }
 
 impl IParameterChanges for ParameterChanges {
     unsafe fn get_parameter_count(&self) -> i32 {
-        0
+        self.get_queue_count() as i32
     }
 
     unsafe fn get_parameter_data(&self, index: i32) -> StaticVstPtr<dyn IParamValueQueue>  {
-        if index != 0 {
+        if index < 0 {
+            return ParamValueQueue::get_null_ptr();
+        }
+
+        match self.get_queue(index as usize) {
+            Some(queue) => queue.get_static_ptr(),
+            None => ParamValueQueue::get_null_ptr()
+        }
+    }
+
+    unsafe fn add_parameter_data(&self, id: *const u32, index: *mut i32,) -> StaticVstPtr<dyn IParamValueQueue>  {
+        if id.is_null() {
             return ParamValueQueue::get_null_ptr();
         }
 
-        ParamValueQueue::get_static_ptr(self.param_value_queue.as_ref())
+        match self.find_or_add_queue(*id) {
+            Some((queue_index, queue)) => {
+                if !index.is_null() {
+                    *index = queue_index as i32;
+                }
+                queue.get_static_ptr()
+            },
+            None => ParamValueQueue::get_null_ptr()
+        }
     }
+}
 
-    unsafe fn add_parameter_data(&self, _id: *const u32, index: *mut i32,) -> StaticVstPtr<dyn IParamValueQueue>  {
-        if !index.is_null() {
-            *index = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(id: ParamID, sample_offset: i32, value: f64) -> ParameterChange {
+        ParameterChange {
+            id,
+            sample_offset,
+            value
         }
+    }
+
+    #[test]
+    fn points_stay_sorted() {
+        let mut changes = ParameterChanges::new();
+
+        assert!(changes.add_change(&change(1, 10, 0.1)).is_ok());
+        assert!(changes.add_change(&change(1, 5, 0.2)).is_ok());
+        assert!(changes.add_change(&change(1, 10, 0.3)).is_ok()); // replaces
+        assert!(changes.add_change(&change(2, 0, 0.4)).is_ok());
 
-        ParamValueQueue::get_static_ptr(self.param_value_queue.as_ref())
+        assert_eq!(changes.get_queue_count(), 2);
+        let queue = changes.get_queue(0).unwrap();
+        assert_eq!(queue.get_id(), 1);
+        assert_eq!(queue.get_points(), &[(5, 0.2), (10, 0.3)]);
+        assert_eq!(queue.get_last_point(), Some((10, 0.3)));
+
+        changes.clear();
+        assert_eq!(changes.get_queue_count(), 0);
+        assert!(changes.get_queue(0).is_none());
+
+        // a reused queue starts empty
+        assert!(changes.add_change(&change(3, 7, 0.5)).is_ok());
+        assert_eq!(changes.get_queue(0).unwrap().get_points(), &[(7, 0.5)]);
+    }
+
+    #[test]
+    fn overflow_hands_change_back() {
+        let mut changes = ParameterChanges::new();
+
+        for offset in 0..MAX_POINT_COUNT as i32 {
+            assert!(changes.add_change(&change(0, offset, 0.0)).is_ok());
+        }
+        let rejected = change(0, MAX_POINT_COUNT as i32, 1.0);
+        assert_eq!(changes.add_change(&rejected), Err(rejected));
+
+        for id in 1..MAX_PARAMETER_COUNT as ParamID {
+            assert!(changes.add_change(&change(id, 0, 0.0)).is_ok());
+        }
+        let rejected = change(MAX_PARAMETER_COUNT as ParamID, 0, 1.0);
+        assert_eq!(changes.add_change(&rejected), Err(rejected));
     }
 }
@@ -5,22 +5,48 @@ use vst3_com::{interfaces::iunknown::IID_IUNKNOWN, sys::GUID, *};
 use vst3_sys::base::*;
 use log::{*};
 
-use crate::{error::Error, utils::{get_file_time, slashify_path}};
+use serde::{Deserialize, Serialize};
 
-const VST_CATEGORY_AUDIO_EFFECT: &str = "Audio Module Class";
-const VST_CATEGORY_COMPONENT_CONTROLLER: &str = "Component Controller Class";
-const VST_CATEGORY_PLUGIN_COMPATIBILITY: &str = "Plugin Compatibility Class";
+use crate::{error::Error, utils::{get_file_time, slashify_path, string_from_char16, string_from_char8}};
 
+pub const VST_CATEGORY_AUDIO_EFFECT: &str = "Audio Module Class";
+pub const VST_CATEGORY_COMPONENT_CONTROLLER: &str = "Component Controller Class";
+pub const VST_CATEGORY_PLUGIN_COMPATIBILITY: &str = "Plugin Compatibility Class";
+const VST_SUB_CATEGORY_INSTRUMENT: &str = "Instrument";
+const VST_SUB_CATEGORY_FX: &str = "Fx";
+
+#[cfg(windows)]
 type FnInitDll = extern "system" fn() -> bool;
+#[cfg(windows)]
 type FnExitDll = extern "system" fn() -> bool;
+#[cfg(not(windows))]
+type FnModuleEntry = extern "C" fn(*mut c_void) -> bool;
+#[cfg(not(windows))]
+type FnModuleExit = extern "C" fn() -> bool;
+
+#[cfg(windows)]
+type FnExitModule = FnExitDll;
+#[cfg(not(windows))]
+type FnExitModule = FnModuleExit;
 type FnGetPluginFactory = extern "system" fn() -> *mut *mut IPluginFactoryVTable;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ClassInfo {
     pub name: String,
     pub category: String,
     pub cardinality: i32,
-    pub cid: GUID
+    #[serde(with = "guid_string")]
+    pub cid: GUID,
+    #[serde(default)]
+    pub vendor: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub sdk_version: String,
+    #[serde(default)]
+    pub sub_categories: Vec<String>,
+    #[serde(default)]
+    pub class_flags: u32
 }
 
 impl ClassInfo {
@@ -31,17 +57,94 @@ impl ClassInfo {
             name,
             category,
             cardinality: ci.cardinality,
-            cid: ci.cid
+            cid: ci.cid,
+            vendor: String::new(),
+            version: String::new(),
+            sdk_version: String::new(),
+            sub_categories: Vec::new(),
+            class_flags: 0
+        }
+    }
+
+    pub fn from_info2(ci: PClassInfo2) -> Self {
+        Self {
+            name: string_from_char8(&ci.name),
+            category: string_from_char8(&ci.category),
+            cardinality: ci.cardinality,
+            cid: ci.cid,
+            vendor: string_from_char8(&ci.vendor),
+            version: string_from_char8(&ci.version),
+            sdk_version: string_from_char8(&ci.sdk_version),
+            sub_categories: split_sub_categories(&string_from_char8(&ci.subcategories)),
+            class_flags: ci.class_flags
+        }
+    }
+
+    pub fn from_info_unicode(ci: PClassInfoW) -> Self {
+        Self {
+            name: string_from_char16(&ci.name),
+            category: string_from_char8(&ci.category),
+            cardinality: ci.cardinality,
+            cid: ci.cid,
+            vendor: string_from_char16(&ci.vendor),
+            version: string_from_char16(&ci.version),
+            sdk_version: string_from_char16(&ci.sdk_version),
+            sub_categories: split_sub_categories(&string_from_char8(&ci.subcategories)),
+            class_flags: ci.class_flags
+        }
+    }
+
+    pub fn has_sub_category(&self, sub_category: &str) -> bool {
+        self.sub_categories.iter().any(|s| s.eq_ignore_ascii_case(sub_category))
+    }
+
+    pub fn is_instrument(&self) -> bool {
+        self.category == VST_CATEGORY_AUDIO_EFFECT && self.has_sub_category(VST_SUB_CATEGORY_INSTRUMENT)
+    }
+
+    pub fn is_effect(&self) -> bool {
+        self.category == VST_CATEGORY_AUDIO_EFFECT && self.has_sub_category(VST_SUB_CATEGORY_FX)
+    }
+}
+
+/// Splits a VST3 sub category string like "Instrument|Synth".
+fn split_sub_categories(s: &str) -> Vec<String> {
+    s.split('|').map(|c| c.trim()).filter(|c| !c.is_empty()).map(|c| c.to_string()).collect()
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FactoryInfo {
+    pub vendor: String,
+    pub url: String,
+    pub email: String,
+    pub flags: i32
+}
+
+impl FactoryInfo {
+    pub fn from(fi: PFactoryInfo) -> Self {
+        Self {
+            vendor: string_from_char8(&fi.vendor),
+            url: string_from_char8(&fi.url),
+            email: string_from_char8(&fi.email),
+            flags: fi.flags
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PluginInfo {
+    #[serde(skip)]
     pub id: String,
     pub path: String,
-    pub classes: Vec<ClassInfo>,
-    pub file_time: i64
+    pub file_time: i64,
+    #[serde(default)]
+    pub blocked: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub factory: FactoryInfo,
+    #[serde(default)]
+    pub classes: Vec<ClassInfo>
 }
 
 impl Default for PluginInfo {
@@ -49,8 +152,25 @@ impl Default for PluginInfo {
         Self {
             id: String::new(),
             path: String::new(),
+            factory: FactoryInfo::default(),
             classes: Vec::new(),
-            file_time: 0
+            file_time: 0,
+            blocked: false,
+            error: None
+        }
+    }
+}
+
+impl PluginInfo {
+    pub fn failed(id: &str, path: &str, file_time: i64, error: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            path: slashify_path(path),
+            factory: FactoryInfo::default(),
+            classes: Vec::new(),
+            file_time,
+            blocked: true,
+            error: Some(error.to_string())
         }
     }
 }
@@ -58,7 +178,7 @@ impl Default for PluginInfo {
 pub struct Plugin {
     info: PluginInfo,
     pub lib: Option<libloading::Library>,
-    exit_fn: Option<FnExitDll>,
+    exit_fn: Option<FnExitModule>,
     factory: RawVstPtr<dyn IPluginFactory>,
 }
 
@@ -82,6 +202,34 @@ impl Plugin {
             }
         };
 
+        let (lib, exit_fn) = Self::enter_module(lib)?;
+
+        let factory = Self::get_factory(&lib)?;
+        let factory_info = Self::get_factory_info(&factory);
+        let classes = Self::get_classes(&factory, &factory_info)?;
+
+        let plugin_info = PluginInfo {
+            id: get_identifier_from_path(filename),
+            path: slashify_path(filename),
+            factory: factory_info,
+            classes,
+            file_time,
+            blocked: false,
+            error: None
+        };
+
+        Ok(Self {
+            info: plugin_info,
+            lib: Some(lib),
+            exit_fn,
+            factory
+        })
+
+    }
+
+    #[cfg(windows)]
+    fn enter_module(lib: Library) -> Result<(Library, Option<FnExitModule>), Error> {
+
         match unsafe { lib.get::<FnInitDll>(b"InitDll") } {
             Ok(init_fn) => {
                 let result = init_fn();
@@ -97,23 +245,35 @@ impl Plugin {
             Err(_e) => None
         };
 
-        let factory = Self::get_factory(&lib)?;
-        let classes = Self::get_classes(&factory)?;
+        Ok((lib, exit_fn))
+    }
 
-        let plugin_info = PluginInfo {
-            id: get_identifier_from_path(filename),
-            path: slashify_path(filename),
-            classes,
-            file_time
+    #[cfg(not(windows))]
+    fn enter_module(lib: Library) -> Result<(Library, Option<FnExitModule>), Error> {
+
+        // ModuleEntry expects the native handle of the shared library
+        let unix_lib: libloading::os::unix::Library = lib.into();
+        let handle = unix_lib.into_raw();
+        let lib: Library = unsafe { libloading::os::unix::Library::from_raw(handle) }.into();
+
+        match unsafe { lib.get::<FnModuleEntry>(b"ModuleEntry") } {
+            Ok(entry_fn) => {
+                let result = entry_fn(handle);
+                if !result {
+                    return Err(Error::from("failed to initialize plugin"));
+                }
+            },
+            Err(_e) => {
+                return Err(Error::from("failed to load plugin library: missing module entry"));
+            }
         };
 
-        Ok(Self {
-            info: plugin_info,
-            lib: Some(lib),
-            exit_fn,
-            factory
-        })
+        let exit_fn = match unsafe { lib.get::<FnModuleExit>(b"ModuleExit") } {
+            Ok(exit_fn) => Some(*exit_fn),
+            Err(_e) => None
+        };
 
+        Ok((lib, exit_fn))
     }
 
     pub fn dispose(&mut self) {
@@ -162,32 +322,103 @@ impl Plugin {
         Ok(factory)
     }
 
-    fn get_classes(plugin_factory: &RawVstPtr<dyn IPluginFactory>) -> Result<Vec<ClassInfo>, Error> {
+    fn get_factory_info(plugin_factory: &RawVstPtr<dyn IPluginFactory>) -> FactoryInfo {
+
+        trace!("get factory info");
+
+        let mut p_factory_info: PFactoryInfo = unsafe { std::mem::zeroed() };
+
+        let result = unsafe { plugin_factory.get_factory_info(&mut p_factory_info) };
+        if result != kResultOk {
+            return FactoryInfo::default();
+        }
+
+        let factory_info = FactoryInfo::from(p_factory_info);
+
+        trace!(" - factory info: vendor: \"{}\", url: \"{}\", email: \"{}\", flags: {:#x}", factory_info.vendor, factory_info.url, factory_info.email, factory_info.flags);
+
+        factory_info
+    }
+
+    fn query_factory<I: ComInterface + ?Sized>(plugin_factory: &RawVstPtr<dyn IPluginFactory>) -> Option<VstPtr<I>> {
+        let mut obj: *mut c_void = null_mut();
+        unsafe {
+            let result = plugin_factory.query_interface(&I::IID, &mut obj);
+            if result != kResultOk {
+                return None;
+            }
+
+            // owned -> released when dropped, balances the query
+            VstPtr::<I>::owned(obj as *mut _)
+        }
+    }
+
+    fn get_classes(plugin_factory: &RawVstPtr<dyn IPluginFactory>, factory_info: &FactoryInfo) -> Result<Vec<ClassInfo>, Error> {
 
         trace!("get classes");
 
         let mut classes =  Vec::<ClassInfo>::new();
 
+        let factory2 = Self::query_factory::<dyn IPluginFactory2>(plugin_factory);
+        let factory3 = Self::query_factory::<dyn IPluginFactory3>(plugin_factory);
+
         unsafe {
 
             let class_count = plugin_factory.count_classes();
 
             for i in 0..class_count {
-                let mut p_class_info = PClassInfo {
-                    cid: GUID { data: [0u8; 16] },
-                    cardinality: 0,
-                    category: [0; 32],
-                    name: [0; 64]
+
+                // prefer the most detailed info the factory supports
+                let mut class_info = None;
+
+                match factory3.as_ref() {
+                    Some(factory3) => {
+                        let mut p_class_info: PClassInfoW = std::mem::zeroed();
+                        if factory3.get_class_info_unicode(i, &mut p_class_info) == kResultOk {
+                            class_info = Some(ClassInfo::from_info_unicode(p_class_info));
+                        }
+                    },
+                    None => {}
                 };
 
-                let result = plugin_factory.get_class_info(i, &mut p_class_info);
-                if result != kResultOk {
-                    continue;
+                if class_info.is_none() {
+                    match factory2.as_ref() {
+                        Some(factory2) => {
+                            let mut p_class_info: PClassInfo2 = std::mem::zeroed();
+                            if factory2.get_class_info2(i, &mut p_class_info) == kResultOk {
+                                class_info = Some(ClassInfo::from_info2(p_class_info));
+                            }
+                        },
+                        None => {}
+                    };
                 }
 
-                let class_info = ClassInfo::from(p_class_info);
+                if class_info.is_none() {
+                    let mut p_class_info = PClassInfo {
+                        cid: GUID { data: [0u8; 16] },
+                        cardinality: 0,
+                        category: [0; 32],
+                        name: [0; 64]
+                    };
 
-                trace!(" - class info: name: \"{}\", category: \"{}\", cardinality: {:#x}", class_info.name, class_info.category, class_info.cardinality);
+                    if plugin_factory.get_class_info(i, &mut p_class_info) == kResultOk {
+                        class_info = Some(ClassInfo::from(p_class_info));
+                    }
+                }
+
+                let mut class_info = match class_info {
+                    Some(class_info) => class_info,
+                    None => {
+                        continue;
+                    }
+                };
+
+                if class_info.vendor.is_empty() {
+                    class_info.vendor = factory_info.vendor.clone();
+                }
+
+                trace!(" - class info: name: \"{}\", category: \"{}\", sub categories: \"{}\", vendor: \"{}\", version: \"{}\", sdk: \"{}\", cardinality: {:#x}",
+                    class_info.name, class_info.category, class_info.sub_categories.join("|"), class_info.vendor, class_info.version, class_info.sdk_version, class_info.cardinality);
 
                 classes.push(class_info);
             }
@@ -303,3 +534,23 @@ pub fn get_identifier_from_path(path: &str) -> String {
 
     s
 }
+
+/// Serializes class ids as 32 digit hex strings.
+mod guid_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use vst3_com::sys::GUID;
+
+    use crate::utils::GuidStringify;
+
+    pub fn serialize<S: Serializer>(guid: &GUID, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&guid.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<GUID, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s.len() != 32 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(de::Error::custom("invalid class id"));
+        }
+        Ok(GUID::from_string(&s))
+    }
+}
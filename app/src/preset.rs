@@ -0,0 +1,383 @@
+//!
+//! VST3 preset file
+//!
+//! Reads and writes the Steinberg `.vstpreset` container:
+//!
+//! ```text
+//! header:     "VST3" | version i32 | class id (32 ascii hex) | chunk list offset i64
+//! data:       chunk data ...
+//! chunk list: "List" | entry count i32 | { chunk id (4) | offset i64 | size i64 } ...
+//! ```
+//!
+//! Chunks are `Comp` (component state), `Cont` (controller state) and
+//! `Info` (meta info XML). All integers are little endian.
+//!
+
+use log::{*};
+use std::fs;
+
+use crate::error::Error;
+
+pub const PRESET_FILE_EXTENSION: &str = "vstpreset";
+
+const PRESET_HEADER_ID: &[u8; 4] = b"VST3";
+const PRESET_FORMAT_VERSION: i32 = 1;
+const PRESET_CLASS_ID_SIZE: usize = 32;
+const PRESET_HEADER_SIZE: usize = 4 + 4 + PRESET_CLASS_ID_SIZE + 8;
+const PRESET_LIST_ID: &[u8; 4] = b"List";
+
+const CHUNK_COMPONENT_STATE: &[u8; 4] = b"Comp";
+const CHUNK_CONTROLLER_STATE: &[u8; 4] = b"Cont";
+const CHUNK_META_INFO: &[u8; 4] = b"Info";
+
+#[derive(Clone, Debug, Default)]
+pub struct PresetMetaInfo {
+    pub name: String,
+    pub author: String,
+    pub plugin_name: String,
+    pub plugin_category: String
+}
+
+impl PresetMetaInfo {
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\r\n");
+        xml.push_str("<MetaInfo>\r\n");
+        Self::push_attribute(&mut xml, "MediaType", "VstPreset");
+        Self::push_attribute(&mut xml, "PlugInName", &self.plugin_name);
+        Self::push_attribute(&mut xml, "PlugInCategory", &self.plugin_category);
+        Self::push_attribute(&mut xml, "Name", &self.name);
+        Self::push_attribute(&mut xml, "Author", &self.author);
+        xml.push_str("</MetaInfo>\r\n");
+        xml
+    }
+
+    /// Picks the known attributes from a meta info XML, unknown ones are ignored.
+    pub fn from_xml(xml: &str) -> Self {
+        let mut meta_info = Self::default();
+
+        for element in xml.split("<Attr").skip(1) {
+            let element = match element.find('>') {
+                Some(end) => &element[..end],
+                None => element
+            };
+
+            let id = Self::get_xml_attribute(element, "id").unwrap_or_default();
+            let value = Self::get_xml_attribute(element, "value").unwrap_or_default();
+
+            match id.as_str() {
+                "PlugInName" => meta_info.plugin_name = value,
+                "PlugInCategory" => meta_info.plugin_category = value,
+                "Name" => meta_info.name = value,
+                "Author" => meta_info.author = value,
+                _ => {}
+            }
+        }
+
+        meta_info
+    }
+
+    fn push_attribute(xml: &mut String, id: &str, value: &str) {
+        if value.is_empty() {
+            return;
+        }
+        xml.push_str(&format!("\t<Attr id=\"{}\" value=\"{}\" type=\"string\" flags=\"writeProtected\"/>\r\n", id, Self::escape(value)));
+    }
+
+    fn get_xml_attribute(element: &str, name: &str) -> Option<String> {
+        let key = format!(" {}=\"", name);
+        let start = element.find(&key)? + key.len();
+        let end = start + element[start..].find('"')?;
+        Some(Self::unescape(&element[start..end]))
+    }
+
+    fn escape(s: &str) -> String {
+        s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+    }
+
+    fn unescape(s: &str) -> String {
+        s.replace("&quot;", "\"").replace("&gt;", ">").replace("&lt;", "<").replace("&amp;", "&")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PresetFile {
+    pub class_id: String,
+    pub component_state: Vec<u8>,
+    pub controller_state: Vec<u8>,
+    pub meta_info: Option<PresetMetaInfo>
+}
+
+impl PresetFile {
+
+    pub fn is_preset_path(path: &str) -> bool {
+        path.to_ascii_lowercase().ends_with(&format!(".{}", PRESET_FILE_EXTENSION))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        trace!("save preset: {}", path);
+
+        let data = self.to_bytes()?;
+
+        match fs::write(path, &data) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::from(format!("failed to write preset file '{}'", path)))
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        trace!("load preset: {}", path);
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::from(format!("failed to read preset file '{}'", path)));
+            }
+        };
+
+        Self::parse(&data)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+
+        let class_id = preset_class_id_from_registry(&self.class_id)?;
+
+        let mut data = Vec::<u8>::new();
+        data.extend_from_slice(PRESET_HEADER_ID);
+        data.extend_from_slice(&PRESET_FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(class_id.as_bytes());
+        data.extend_from_slice(&0i64.to_le_bytes()); // patched below
+
+        let mut entries = Vec::<(&[u8; 4], usize, usize)>::new();
+
+        entries.push((CHUNK_COMPONENT_STATE, data.len(), self.component_state.len()));
+        data.extend_from_slice(&self.component_state);
+
+        if !self.controller_state.is_empty() {
+            entries.push((CHUNK_CONTROLLER_STATE, data.len(), self.controller_state.len()));
+            data.extend_from_slice(&self.controller_state);
+        }
+
+        match self.meta_info.as_ref() {
+            Some(meta_info) => {
+                let xml = meta_info.to_xml();
+                entries.push((CHUNK_META_INFO, data.len(), xml.len()));
+                data.extend_from_slice(xml.as_bytes());
+            },
+            None => {}
+        };
+
+        let list_offset = data.len() as i64;
+        data[PRESET_HEADER_SIZE - 8..PRESET_HEADER_SIZE].copy_from_slice(&list_offset.to_le_bytes());
+
+        data.extend_from_slice(PRESET_LIST_ID);
+        data.extend_from_slice(&(entries.len() as i32).to_le_bytes());
+        for (id, offset, size) in entries {
+            data.extend_from_slice(id);
+            data.extend_from_slice(&(offset as i64).to_le_bytes());
+            data.extend_from_slice(&(size as i64).to_le_bytes());
+        }
+
+        Ok(data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+
+        if data.len() < PRESET_HEADER_SIZE || &data[0..4] != PRESET_HEADER_ID {
+            return Err(Error::from("invalid preset file"));
+        }
+
+        let class_id = match std::str::from_utf8(&data[8..8 + PRESET_CLASS_ID_SIZE]) {
+            Ok(class_id) => registry_class_id_from_preset(class_id)?,
+            Err(_) => {
+                return Err(Error::from("invalid class id in preset file"));
+            }
+        };
+
+        let list_offset = match get_range(data, read_i64(data, PRESET_HEADER_SIZE - 8)?, 8) {
+            Some(range) if &data[range.start..range.start + 4] == PRESET_LIST_ID => range.start,
+            _ => {
+                return Err(Error::from("invalid chunk list in preset file"));
+            }
+        };
+
+        let mut preset = Self {
+            class_id,
+            ..Default::default()
+        };
+
+        let entry_count = read_i32(data, list_offset + 4)?;
+
+        let mut offset = list_offset + 8;
+        for _ in 0..entry_count {
+            if offset + 20 > data.len() {
+                return Err(Error::from("truncated preset file"));
+            }
+
+            let id = &data[offset..offset + 4];
+            let chunk_offset = read_i64(data, offset + 4)?;
+            let chunk_size = read_i64(data, offset + 12)?;
+            offset += 20;
+
+            let chunk = match get_range(data, chunk_offset, chunk_size) {
+                Some(range) => &data[range],
+                None => {
+                    return Err(Error::from("invalid chunk in preset file"));
+                }
+            };
+
+            if id == CHUNK_COMPONENT_STATE {
+                preset.component_state = chunk.to_vec();
+            } else if id == CHUNK_CONTROLLER_STATE {
+                preset.controller_state = chunk.to_vec();
+            } else if id == CHUNK_META_INFO {
+                preset.meta_info = Some(PresetMetaInfo::from_xml(&String::from_utf8_lossy(chunk)));
+            } else {
+                trace!("skipping preset chunk {:?}", String::from_utf8_lossy(id));
+            }
+        }
+
+        Ok(preset)
+    }
+}
+
+/// Validates an offset and size read from the file against the data, without overflowing.
+fn get_range(data: &[u8], offset: i64, size: i64) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    let end = start.checked_add(usize::try_from(size).ok()?)?;
+    if end > data.len() {
+        return None;
+    }
+    Some(start..end)
+}
+
+fn read_i32(data: &[u8], offset: usize) -> Result<i32, Error> {
+    if offset + 4 > data.len() {
+        return Err(Error::from("truncated preset file"));
+    }
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_i64(data: &[u8], offset: usize) -> Result<i64, Error> {
+    if offset + 8 > data.len() {
+        return Err(Error::from("truncated preset file"));
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    Ok(i64::from_le_bytes(bytes))
+}
+
+/// The registry prints class ids in memory byte order. Presets use the SDK
+/// string form, which on COM compatible platforms (Windows) prints the
+/// first three GUID fields as little endian integers.
+fn preset_class_id_from_registry(class_id: &str) -> Result<String, Error> {
+    if class_id.len() != PRESET_CLASS_ID_SIZE || !class_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::from(format!("invalid class id '{}'", class_id)));
+    }
+    Ok(swap_guid_fields(&class_id.to_ascii_uppercase()))
+}
+
+fn registry_class_id_from_preset(class_id: &str) -> Result<String, Error> {
+    // swapping the fields is its own inverse
+    preset_class_id_from_registry(class_id)
+}
+
+#[cfg(windows)]
+fn swap_guid_fields(class_id: &str) -> String {
+    let swap = |s: &str| -> String {
+        let bytes = s.as_bytes().chunks(2).rev().map(|c| std::str::from_utf8(c).unwrap()).collect::<Vec<&str>>();
+        bytes.join("")
+    };
+    format!("{}{}{}{}", swap(&class_id[0..8]), swap(&class_id[8..12]), swap(&class_id[12..16]), &class_id[16..])
+}
+
+#[cfg(not(windows))]
+fn swap_guid_fields(class_id: &str) -> String {
+    class_id.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASS_ID: &str = "0123456789ABCDEF0123456789ABCDEF";
+
+    fn preset() -> PresetFile {
+        PresetFile {
+            class_id: CLASS_ID.to_string(),
+            component_state: vec![1, 2, 3, 4],
+            controller_state: vec![5, 6],
+            meta_info: Some(PresetMetaInfo {
+                name: "Lead <1> & \"2\"".to_string(),
+                author: "keystone".to_string(),
+                plugin_name: "Synth".to_string(),
+                plugin_category: "Instrument|Synth".to_string()
+            })
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = preset();
+        let parsed = PresetFile::parse(&original.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed.class_id, original.class_id);
+        assert_eq!(parsed.component_state, original.component_state);
+        assert_eq!(parsed.controller_state, original.controller_state);
+
+        let meta_info = parsed.meta_info.unwrap();
+        let original_meta_info = original.meta_info.unwrap();
+        assert_eq!(meta_info.name, original_meta_info.name);
+        assert_eq!(meta_info.author, original_meta_info.author);
+        assert_eq!(meta_info.plugin_name, original_meta_info.plugin_name);
+        assert_eq!(meta_info.plugin_category, original_meta_info.plugin_category);
+    }
+
+    #[test]
+    fn round_trip_without_optional_chunks() {
+        let original = PresetFile {
+            class_id: CLASS_ID.to_string(),
+            component_state: vec![7; 100],
+            ..Default::default()
+        };
+        let parsed = PresetFile::parse(&original.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed.component_state, original.component_state);
+        assert!(parsed.controller_state.is_empty());
+        assert!(parsed.meta_info.is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = preset().to_bytes().unwrap();
+
+        for len in [0, PRESET_HEADER_SIZE - 1, PRESET_HEADER_SIZE, data.len() - 1] {
+            assert!(PresetFile::parse(&data[..len]).is_err(), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_chunk() {
+        let mut data = preset().to_bytes().unwrap();
+
+        // first chunk list entry: id at list + 8, offset at list + 12, size at list + 20
+        let list_offset = read_i64(&data, PRESET_HEADER_SIZE - 8).unwrap() as usize;
+        data[list_offset + 12..list_offset + 20].copy_from_slice(&i64::MAX.to_le_bytes());
+        data[list_offset + 20..list_offset + 28].copy_from_slice(&i64::MAX.to_le_bytes());
+        assert!(PresetFile::parse(&data).is_err());
+
+        data[list_offset + 12..list_offset + 20].copy_from_slice(&(-1i64).to_le_bytes());
+        assert!(PresetFile::parse(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_list_offset() {
+        let mut data = preset().to_bytes().unwrap();
+
+        data[PRESET_HEADER_SIZE - 8..PRESET_HEADER_SIZE].copy_from_slice(&i64::MAX.to_le_bytes());
+        assert!(PresetFile::parse(&data).is_err());
+    }
+}
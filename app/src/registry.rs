@@ -1,16 +1,79 @@
-use std::{collections::HashMap, fs::{self, File}, io::{Read, Write}, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs::{self, File}, io::{Read, Write}, path::{Path, PathBuf}, sync::Arc};
 
 use chrono::Utc;
 use vst3_com::sys::GUID;
 use log::{*};
 
-use crate::{config::{REGISTRY_CACHE_FILENAME, VST_DIRS}, error::Error, instance::Instance, plugin::{get_identifier_from_class, get_identifier_from_path, ClassInfo, Plugin, PluginInfo}, utils::get_file_time};
+use crate::{bundle::{expand_home_dir, is_bundle_path, resolve_library_path}, config::{REGISTRY_CACHE_FILENAME, REGISTRY_SCAN_FOLLOW_SYMLINKS, REGISTRY_SCAN_OUT_OF_PROCESS, REGISTRY_SCAN_MAX_DEPTH, REGISTRY_SCAN_USE_MODULE_INFO, VST_DIRS}, error::Error, instance::Instance, module_info::ModuleInfo, plugin::{get_identifier_from_path, ClassInfo, VST_CATEGORY_AUDIO_EFFECT, Plugin, PluginInfo}, scanner::Scanner, utils::{get_file_time, slashify_path, GuidStringify}};
+
+const REGISTRY_CACHE_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct CacheHeader {
+    version: u32,
+    timestamp: String
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    cache: CacheHeader,
+    plugins: BTreeMap<String, PluginInfo>
+}
 
 pub struct ClassMapEntry {
     plugin_info: PluginInfo,
     class_info: ClassInfo
 }
 
+impl ClassMapEntry {
+    pub fn plugin_info(&self) -> &PluginInfo {
+        &self.plugin_info
+    }
+
+    pub fn class_info(&self) -> &ClassInfo {
+        &self.class_info
+    }
+
+    pub fn class_id(&self) -> String {
+        self.class_info.cid.to_string()
+    }
+}
+
+/// Filter for `Registry::find_classes`. Unset fields match everything,
+/// string comparisons ignore case.
+#[derive(Clone, Debug, Default)]
+pub struct ClassQuery {
+    pub name: Option<String>,
+    pub vendor: Option<String>,
+    pub category: Option<String>,
+    pub sub_category: Option<String>
+}
+
+impl ClassQuery {
+    pub fn matches(&self, class_info: &ClassInfo) -> bool {
+        Self::matches_field(&self.name, &class_info.name) &&
+        Self::matches_field(&self.vendor, &class_info.vendor) &&
+        Self::matches_field(&self.category, &class_info.category) &&
+        match self.sub_category.as_ref() {
+            Some(sub_category) => class_info.has_sub_category(sub_category),
+            None => true
+        }
+    }
+
+    fn matches_field(filter: &Option<String>, value: &str) -> bool {
+        match filter {
+            Some(filter) => filter.eq_ignore_ascii_case(value),
+            None => true
+        }
+    }
+}
+
+pub struct LibraryLocation {
+    pub library_path: String,
+    pub search_dir: String
+}
+
 pub struct PluginRef {
     plugin: Arc<Plugin>,
     ref_counter: usize
@@ -43,8 +106,19 @@ impl Registry {
         trace!("init");
 
         self.update_cache()?;
+        self.update_class_map();
+
+        Ok(())
+    }
+
+    fn update_class_map(&mut self) {
+
+        self.class_map.clear();
 
         for plugin_info in self.cache.values() {
+            if plugin_info.blocked {
+                continue;
+            }
             for class_info in &plugin_info.classes {
                 let entry = ClassMapEntry {
                     plugin_info: plugin_info.clone(),
@@ -55,8 +129,130 @@ impl Registry {
                 self.class_map.insert(uid, entry);
             }
         }
+    }
 
-        Ok(())
+    pub fn list_plugins(&self) -> Vec<&PluginInfo> {
+        let mut plugins = self.cache.values().collect::<Vec<&PluginInfo>>();
+        plugins.sort_by(|a, b| a.path.cmp(&b.path));
+        plugins
+    }
+
+    pub fn list_blocked_plugins(&self) -> Vec<&PluginInfo> {
+        self.list_plugins().into_iter().filter(|plugin_info| plugin_info.blocked).collect()
+    }
+
+    pub fn block_plugin(&mut self, plugin_id: &str) -> Result<(), Error> {
+        trace!("block plugin {}", plugin_id);
+
+        match self.cache.get_mut(plugin_id) {
+            Some(plugin_info) => {
+                plugin_info.blocked = true;
+            },
+            None => {
+                return Err(Error::from("invalid plugin identifier"));
+            }
+        };
+
+        self.update_class_map();
+        Self::write_cache(&self.cache)
+    }
+
+    /// Unblocks a plugin. Plugins that failed to load are rescanned.
+    pub fn unblock_plugin(&mut self, plugin_id: &str) -> Result<(), Error> {
+        trace!("unblock plugin {}", plugin_id);
+
+        let failed = match self.cache.get_mut(plugin_id) {
+            Some(plugin_info) => {
+                plugin_info.blocked = false;
+                plugin_info.error.is_some()
+            },
+            None => {
+                return Err(Error::from("invalid plugin identifier"));
+            }
+        };
+
+        if failed {
+            return self.rescan_plugin(plugin_id);
+        }
+
+        self.update_class_map();
+        Self::write_cache(&self.cache)
+    }
+
+    pub fn rescan_plugin(&mut self, plugin_id: &str) -> Result<(), Error> {
+        trace!("rescan plugin {}", plugin_id);
+
+        let previous_plugin_info = match self.cache.get(plugin_id) {
+            Some(plugin_info) => plugin_info.clone(),
+            None => {
+                return Err(Error::from("invalid plugin identifier"));
+            }
+        };
+
+        if self.loaded_plugins.contains_key(plugin_id) {
+            return Err(Error::from("cannot rescan plugin while it is loaded"));
+        }
+
+        let plugin_info = Self::scan_plugin(&previous_plugin_info.path, Some(&previous_plugin_info));
+        let result = match &plugin_info.error {
+            Some(error) => Err(Error::from(error)),
+            None => Ok(())
+        };
+
+        self.cache.insert(plugin_id.to_string(), plugin_info);
+
+        self.update_class_map();
+        let _ = Self::write_cache(&self.cache);
+
+        result
+    }
+
+    fn scan_plugin(filename: &str, previous_plugin_info: Option<&PluginInfo>) -> PluginInfo {
+
+        let identifier = get_identifier_from_path(filename);
+        let file_time = get_file_time(filename);
+
+        // prefer the bundle module info, loading the library is slow and may crash
+        let module_info = if REGISTRY_SCAN_USE_MODULE_INFO {
+            match ModuleInfo::load(filename) {
+                Ok(module_info) => module_info,
+                Err(e) => {
+                    warn!("ignoring module info of {}: {}", filename, e.message());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let scan_result = match module_info {
+            Some(plugin_info) => Ok(plugin_info),
+            None => {
+                if REGISTRY_SCAN_OUT_OF_PROCESS {
+                    Scanner::scan_out_of_process(filename)
+                } else {
+                    Scanner::scan_in_process(filename)
+                }
+            }
+        };
+
+        match scan_result {
+            Ok(mut plugin_info) => {
+                // keep plugins blocked by the user blocked after updates
+                match previous_plugin_info {
+                    Some(previous_plugin_info) => {
+                        plugin_info.blocked = previous_plugin_info.blocked && previous_plugin_info.error.is_none();
+                    },
+                    None => {}
+                };
+                plugin_info
+            },
+            Err(e) => {
+                // failed to load plugin, keep as blocked entry to avoid crashing again
+                warn!("blocking plugin {}: {}", filename, e.message());
+                PluginInfo::failed(&identifier, filename, file_time, e.message())
+            }
+        }
     }
 
     fn ref_plugin(&mut self, plugin_id: &str) -> Result<Arc<Plugin>, Error> {
@@ -71,6 +267,10 @@ impl Registry {
                 }
             };
 
+            if plugin_info.blocked {
+                return Err(Error::from("plugin is blocked"));
+            }
+
             let plugin = Arc::new(Plugin::new(&plugin_info.path)?);
 
             let plugin_ref = PluginRef {
@@ -110,6 +310,40 @@ impl Registry {
         }
     }
 
+    pub fn find_classes(&self, query: &ClassQuery) -> Vec<&ClassMapEntry> {
+        let mut entries = self.class_map.values().filter(|entry| query.matches(&entry.class_info)).collect::<Vec<&ClassMapEntry>>();
+        entries.sort_by(|a, b| a.class_info.name.cmp(&b.class_info.name).then(a.plugin_info.path.cmp(&b.plugin_info.path)));
+        entries
+    }
+
+    /// Resolves a class id (hex, with or without dashes and braces) or a
+    /// unique class name to the class id used by `create_class_instance`.
+    pub fn resolve_class_id(&self, name_or_id: &str) -> Result<String, Error> {
+
+        let class_id = name_or_id.chars().filter(|c| *c != '-' && *c != '{' && *c != '}').collect::<String>().to_ascii_uppercase();
+        if self.class_map.contains_key(&class_id) {
+            return Ok(class_id);
+        }
+
+        let query = ClassQuery {
+            name: Some(name_or_id.trim().to_string()),
+            ..Default::default()
+        };
+
+        let mut entries = self.find_classes(&query);
+
+        // a component and its controller may share the name, prefer the component
+        if entries.len() > 1 {
+            entries.retain(|entry| entry.class_info.category == VST_CATEGORY_AUDIO_EFFECT);
+        }
+
+        match entries.len() {
+            0 => Err(Error::from(format!("no plugin class named '{}'", name_or_id))),
+            1 => Ok(entries[0].class_id()),
+            _ => Err(Error::from(format!("plugin class name '{}' is ambiguous, use the class id", name_or_id)))
+        }
+    }
+
     fn get_class(&self, class_id: &str) -> Result<&ClassMapEntry, Error> {
         match self.class_map.get(class_id) {
             Some(entry) => Ok(entry),
@@ -119,10 +353,13 @@ impl Registry {
         }
     }
 
-    pub fn create_class_instance(&mut self, class_id: &str) -> Result<Instance, Error> {
+    pub fn create_class_instance(&mut self, name_or_id: &str) -> Result<Instance, Error> {
 
         trace!("create class instance");
 
+        let class_id = self.resolve_class_id(name_or_id)?;
+        let class_id = class_id.as_str();
+
         let plugin_id;
         let class_guid: GUID;
 
@@ -198,7 +435,9 @@ impl Registry {
 
         let mut dirty = false;
 
-        for filename in &path_list {
+        for location in &path_list {
+
+            let filename = &location.library_path;
 
             let file_time = get_file_time(filename);
             if 0 == file_time {
@@ -208,21 +447,16 @@ impl Registry {
             let identifier = get_identifier_from_path(filename);
 
             let opt_plugin_info = cache.remove(&identifier);
-            if opt_plugin_info.is_some() && opt_plugin_info.as_ref().unwrap().file_time == file_time {
-                // no change, just move entry to new cache
-                new_cache.insert(identifier, opt_plugin_info.unwrap());
-            } else {
-                dirty = true; // from now on, cache must be updated
-                match Plugin::new(filename) {
-                    Ok(mut plugin) => {
-                        let plugin_info = plugin.get_info().clone();
-                        new_cache.insert(identifier, plugin_info);
-                        plugin.dispose();
-                    },
-                    Err(_) => {
-                        // failed to load plugin, ignore, no update to new cache
-                    }
-                };
+            match opt_plugin_info {
+                Some(plugin_info) if !Self::needs_rescan(&plugin_info, file_time) => {
+                    // no change, just move entry to new cache
+                    new_cache.insert(identifier, plugin_info);
+                },
+                _ => {
+                    dirty = true; // from now on, cache must be updated
+                    let plugin_info = Self::scan_plugin(filename, opt_plugin_info.as_ref());
+                    new_cache.insert(identifier, plugin_info);
+                }
             }
 
             // keep updating cache while processing
@@ -245,6 +479,15 @@ impl Registry {
         Ok(())
     }
 
+    fn needs_rescan(plugin_info: &PluginInfo, file_time: i64) -> bool {
+        if plugin_info.file_time != file_time {
+            return true;
+        }
+
+        // failed plugins unblocked by editing the cache get another try
+        plugin_info.error.is_some() && !plugin_info.blocked
+    }
+
     fn read_cache() -> Result<HashMap<String, PluginInfo>, Error> {
         let mut file = match File::open(REGISTRY_CACHE_FILENAME) {
             Ok(f) => f,
@@ -259,66 +502,47 @@ impl Registry {
             }
         }
 
-        let cache: toml::Table = toml::from_str(&s).unwrap();
-        match cache.get("cache") {
-            Some(_) => {},
-            None => { return Err(Error::from("incomplete plugin cache")); }
+        let cache: toml::Table = match toml::from_str(&s) {
+            Ok(cache) => cache,
+            Err(_) => {
+                warn!("plugin cache is corrupt, rescanning");
+                return Err(Error::from("corrupt plugin cache"));
+            }
+        };
+
+        let header = match cache.get("cache").and_then(|value| value.clone().try_into::<CacheHeader>().ok()) {
+            Some(header) => header,
+            None => {
+                warn!("plugin cache has no valid header, rescanning");
+                return Err(Error::from("incomplete plugin cache"));
+            }
         };
 
+        if header.version != REGISTRY_CACHE_VERSION {
+            debug!("plugin cache version {} does not match {}, rescanning", header.version, REGISTRY_CACHE_VERSION);
+            return Err(Error::from("plugin cache version mismatch"));
+        }
+
         let mut plugins = HashMap::<String, PluginInfo>::new();
 
-        for cache_section in &cache {
-            let section_name = cache_section.0;
-            if section_name == "cache" {
-                continue; // skip
+        let cached_plugins = match cache.get("plugins").and_then(|value| value.as_table()) {
+            Some(cached_plugins) => cached_plugins,
+            None => {
+                return Ok(plugins);
             }
-            let cached_plugin_id = cache_section.0;
-            let cached_plugin_info = cache_section.1;
-            if cached_plugin_info.is_table() {
-                let plugin_attributes = cached_plugin_info.as_table().unwrap();
-
-                let mut plugin_info = PluginInfo::default();
-                plugin_info.id = cached_plugin_id.clone();
-
-                for attribute in plugin_attributes {
-                    let key = attribute.0;
-                    let value = attribute.1;
-
-                    if key == "path" {
-                        plugin_info.path = String::from(value.as_str().unwrap());
-                        //trace!("class name: {}", &plugin_info.path);
-                    } else if key == "file_time" {
-                        plugin_info.file_time = value.as_integer().unwrap() as i64;
-                    } else if value.is_table() {
-                        let info = value.as_table().unwrap();
-                        let name = info.get("name").unwrap().as_str().unwrap();
-                        let category = info.get("category").unwrap().as_str().unwrap();
-                        let cardinality = info.get("cardinality").unwrap().as_integer().unwrap() as i32;
-                        let cid_str = info.get("cid").unwrap().as_str().unwrap();
-                        let cid = GUID::from_string(cid_str);
-
-                        /*
-                        trace!(
-                            "class: name=\"{}\", category=\"{}\", cardinality={}, cid=\"{}\"",
-                            name, category, cardinality, cid_str
-                        );
-                        */
-
-                        let class_info = ClassInfo {
-                            name: name.to_string(),
-                            category: category.to_string(),
-                            cardinality,
-                            cid
-                        };
+        };
 
-                        plugin_info.classes.push(class_info);
-                    }
+        for (cached_plugin_id, cached_plugin_info) in cached_plugins {
+            // a broken entry only invalidates itself, not the whole cache
+            match cached_plugin_info.clone().try_into::<PluginInfo>() {
+                Ok(mut plugin_info) => {
+                    plugin_info.id = cached_plugin_id.clone();
+                    plugins.insert(cached_plugin_id.clone(), plugin_info);
+                },
+                Err(e) => {
+                    warn!("ignoring invalid plugin cache entry {}: {}", cached_plugin_id, e.message());
                 }
-
-                //plugins.push(plugin_info);
-                plugins.insert(cached_plugin_id.clone(), plugin_info);
-
-            }
+            };
         }
 
         Ok(plugins)
@@ -326,67 +550,71 @@ impl Registry {
 
     fn write_cache(plugins: &HashMap<String, PluginInfo>) -> Result<(), Error> {
 
-        let timestamp = Utc::now();
-        let timestamp_str = timestamp.to_string();
-
-        let mut file = match File::create(REGISTRY_CACHE_FILENAME) {
-            Ok(f) => f,
-            Err(_) => { return Err(Error::from("failed to create plugin cache")); }
+        let cache = CacheFile {
+            cache: CacheHeader {
+                version: REGISTRY_CACHE_VERSION,
+                timestamp: Utc::now().to_string()
+            },
+            plugins: plugins.iter().map(|(id, plugin_info)| (id.clone(), plugin_info.clone())).collect()
         };
 
-        match file.write_all(format!("[cache]\ntimestamp = \"{}\"\n\n", timestamp_str).as_bytes()) {
-            Ok(_) => {},
-            Err(_) => { return Err(Error::from("failed to create plugin cache")); }
-        }
+        let s = match toml::to_string(&cache) {
+            Ok(s) => s,
+            Err(_) => { return Err(Error::from("failed to serialize plugin cache")); }
+        };
 
-        for (plugin_identifier, plugin) in plugins.iter() {
+        // write to a temporary file first, so an interrupted write cannot corrupt the cache
+        let temp_filename = format!("{}.tmp", REGISTRY_CACHE_FILENAME);
 
-            //trace!("plugin: {}", plugin.path);
-            let plugin_path = &plugin.path;
+        {
+            let mut file = match File::create(&temp_filename) {
+                Ok(f) => f,
+                Err(_) => { return Err(Error::from("failed to create plugin cache")); }
+            };
 
-            match file.write_all(format!(
-                "[{}]\npath = \"{}\"\nfile_time = {}\n",
-                plugin_identifier,
-                plugin_path,
-                plugin.file_time
-            ).as_bytes()) {
+            match file.write_all(s.as_bytes()) {
                 Ok(_) => {},
                 Err(_) => { return Err(Error::from("failed to create plugin cache")); }
             }
 
-            for class_info in &plugin.classes {
-                match file.write_all(
-                    format!("{} = {{ name=\"{}\", category=\"{}\", cardinality={}, cid=\"{}\" }}\n",
-                    get_identifier_from_class(class_info, Some("class")),
-                    class_info.name,
-                    class_info.category,
-                    class_info.cardinality,
-                    class_info.cid.to_string()
-                ).as_bytes()) {
-                    Ok(_) => {},
-                    Err(_) => { return Err(Error::from("failed to create plugin cache")); }
-                }
-            }
-
-            let _ = file.write_all("\n".as_bytes());
+            let _ = file.sync_all();
         }
 
-        let _ = file.sync_all();
+        match fs::rename(&temp_filename, REGISTRY_CACHE_FILENAME) {
+            Ok(_) => {},
+            Err(_) => { return Err(Error::from("failed to create plugin cache")); }
+        }
 
         Ok(())
     }
 
-    pub fn find_libraries() -> Result<Vec<String>, Error> {
-        let mut path_list = Vec::<String>::new();
+    pub fn find_libraries() -> Result<Vec<LibraryLocation>, Error> {
+        let mut path_list = Vec::<LibraryLocation>::new();
+        let mut visited = HashSet::<PathBuf>::new();
 
         for vst_dir in VST_DIRS {
-            let _ = Self::find_libraries_in_path(vst_dir, &mut path_list);
+            let search_dir = expand_home_dir(vst_dir);
+            let _ = Self::find_libraries_in_path(&search_dir, Path::new(&search_dir), 0, &mut visited, &mut path_list);
         }
 
         Ok(path_list)
     }
 
-    fn find_libraries_in_path(path: &str, path_list: &mut Vec<String>) -> Result<(), Error> {
+    fn find_libraries_in_path(search_dir: &str, path: &Path, depth: usize, visited: &mut HashSet<PathBuf>, path_list: &mut Vec<LibraryLocation>) -> Result<(), Error> {
+
+        // canonical paths detect symlink loops and folders reachable twice
+        let canonical_path = match fs::canonicalize(path) {
+            Ok(p) => p,
+            Err(_) => {
+                return Err(Error::from("failed to resolve directory"));
+            }
+        };
+
+        if !visited.insert(canonical_path) {
+            trace!("skipping already visited directory: {}", path.display());
+            return Ok(());
+        }
+
         let paths = match fs::read_dir(path) {
             Ok(paths) => paths,
             Err(_) => {
@@ -398,62 +626,55 @@ impl Registry {
             match path {
                 Ok(dir_entry) => {
                     let path = dir_entry.path();
-                    match path.extension() {
-                        Some(ext) => {
-                            if ext.to_ascii_lowercase() == "vst3" {
-                                let vst_path = path.to_str().unwrap().to_string();
-                                path_list.push(vst_path)
-                            }
-                        },
-                        None => {}
-                    }
-                }
-                Err(_) => {}
-            }
-        }
-
-        Ok(())
-    }
-
-}
-
-trait GuidStringify {
-    fn to_string(&self) -> String;
-    fn from_string(s: &str) -> GUID;
-}
-
-impl GuidStringify for GUID {
-
-    fn to_string(&self) -> String {
-        self.data.iter().map(|n| format!("{:02X?}", n) ).collect::<Vec<String>>().join("")
-    }
 
-    fn from_string(s: &str) -> Self {
+                    let is_symlink = match dir_entry.file_type() {
+                        Ok(file_type) => file_type.is_symlink(),
+                        Err(_) => false
+                    };
 
-        let mut data: [u8; 16] = [0; 16];
-
-        if s.len() > 0 && s.len() <= 32 {
-            let mut low_nibble = false;
-            let mut byte_index = 0;
-            let mut accumulator: u8 = 0x0;
-            for c in s.chars() {
+                    if is_symlink && !REGISTRY_SCAN_FOLLOW_SYMLINKS {
+                        trace!("skipping symlink: {}", path.display());
+                        continue;
+                    }
 
-                accumulator <<= 4;
-                accumulator += c.to_digit(16).unwrap_or(0) as u8;
+                    if is_bundle_path(&path) {
+                        match fs::canonicalize(&path) {
+                            Ok(canonical_bundle_path) => {
+                                if !visited.insert(canonical_bundle_path) {
+                                    continue; // same bundle reachable via several paths
+                                }
+                            },
+                            Err(_) => {
+                                continue; // dangling symlink
+                            }
+                        };
 
-                if low_nibble {
-                    data[byte_index] = accumulator;
-                    byte_index += 1;
-                    accumulator = 0x0;
+                        match resolve_library_path(&path) {
+                            Some(library_path) => {
+                                let location = LibraryLocation {
+                                    library_path: slashify_path(&library_path.to_string_lossy()),
+                                    search_dir: search_dir.to_string()
+                                };
+                                trace!("found plugin: {} (in {})", location.library_path, location.search_dir);
+                                path_list.push(location);
+                            },
+                            None => {
+                                trace!("no library for this platform in bundle: {}", path.display());
+                            }
+                        }
+                    } else if path.is_dir() {
+                        if depth >= REGISTRY_SCAN_MAX_DEPTH {
+                            trace!("maximum scan depth reached: {}", path.display());
+                            continue;
+                        }
+                        let _ = Self::find_libraries_in_path(search_dir, &path, depth + 1, visited, path_list);
+                    }
                 }
-                low_nibble = !low_nibble;
+                Err(_) => {}
             }
         }
 
-        Self {
-            data
-        }
-
+        Ok(())
     }
 
 }
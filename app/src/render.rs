@@ -0,0 +1,202 @@
+//!
+//! Offline render
+//!
+//! Renders a Standard MIDI File through an instrument faster than realtime
+//! and writes the output to a WAV file.
+//!
+
+use log::{*};
+use std::{ffi::c_void, time::Instant};
+use vst3_sys::vst::ProcessModes;
+
+use crate::{audio::{AudioCallbackInfo, AudioFormatInfo, SampleFormat}, audio_processor::Tail, config::{RENDER_BLOCK_SIZE, RENDER_MAX_TAIL_SECONDS, RENDER_SAMPLE_RATE}, error::Error, events::{EventList, NoteIds}, host::Host, instance::Instance, instrument::Instrument, midi_file::MidiFile, registry::Registry, wav::write_wav_float};
+
+const MAX_EVENTS_PER_BLOCK: usize = 256;
+
+pub struct RenderSettings {
+    pub class_id: String,
+    pub midi_path: String,
+    pub wav_path: String,
+    pub sample_rate: f64,
+    pub block_size: usize,
+    pub max_tail_seconds: f64
+}
+
+impl RenderSettings {
+    pub fn new(class_id: &str, midi_path: &str, wav_path: &str) -> Self {
+        Self {
+            class_id: class_id.to_string(),
+            midi_path: midi_path.to_string(),
+            wav_path: wav_path.to_string(),
+            sample_rate: RENDER_SAMPLE_RATE,
+            block_size: RENDER_BLOCK_SIZE,
+            max_tail_seconds: RENDER_MAX_TAIL_SECONDS
+        }
+    }
+}
+
+pub struct OfflineRenderer {
+}
+
+impl OfflineRenderer {
+
+    pub fn render(registry: &mut Registry, host: &Host, settings: &RenderSettings) -> Result<(), Error> {
+        trace!("render");
+
+        if settings.block_size == 0 || settings.sample_rate <= 0.0 {
+            return Err(Error::from("invalid render settings"));
+        }
+
+        let midi_file = MidiFile::load(&settings.midi_path)?;
+
+        let audio_format = AudioFormatInfo {
+            sample_rate: settings.sample_rate,
+            num_channels: 2,
+            buffer_size: settings.block_size,
+            sample_format: SampleFormat::Float32
+        };
+
+        trace!("create instance");
+        let instance = registry.create_class_instance(&settings.class_id)?;
+
+        let result = match instance.initialize(host) {
+            Ok(_) => Self::render_instance(&instance, host, &audio_format, &midi_file, settings),
+            Err(e) => Err(e)
+        };
+
+        let _ = instance.terminate();
+        let _ = registry.unref_class_instance(instance);
+
+        let samples = result?;
+
+        write_wav_float(&settings.wav_path, settings.sample_rate as u32, audio_format.num_channels as u16, &samples)?;
+
+        debug!("rendered {} samples to '{}'", samples.len() / audio_format.num_channels, settings.wav_path);
+
+        Ok(())
+    }
+
+    fn render_instance(instance: &Instance, host: &Host, audio_format: &AudioFormatInfo, midi_file: &MidiFile, settings: &RenderSettings) -> Result<Vec<f32>, Error> {
+
+        trace!("create instrument");
+        let mut instrument = Instrument::new(instance, host, audio_format, ProcessModes::kOffline as i32)?;
+
+        instrument.set_active(true)?;
+        instrument.set_processing(true)?;
+
+        let result = Self::render_blocks(&mut instrument, audio_format, midi_file, settings);
+
+        let _ = instrument.set_processing(false);
+        let _ = instrument.set_active(false);
+        instrument.dispose();
+
+        result
+    }
+
+    fn render_blocks(instrument: &mut Instrument, audio_format: &AudioFormatInfo, midi_file: &MidiFile, settings: &RenderSettings) -> Result<Vec<f32>, Error> {
+
+        let sample_rate = audio_format.sample_rate;
+        let block_size = audio_format.buffer_size;
+
+        let event_positions = midi_file.events.iter().map(|e| {
+            (e.time * sample_rate).round() as usize
+        }).collect::<Vec<usize>>();
+
+        let events_end = (midi_file.duration * sample_rate).round() as usize;
+        let max_tail = (settings.max_tail_seconds * sample_rate) as usize;
+
+        let tail_samples = match instrument.get_tail() {
+            Tail::None => 0,
+            Tail::Samples(samples) => samples.min(max_tail),
+            Tail::Infinite => max_tail
+        };
+
+        let total_samples = events_end + tail_samples;
+
+        trace!("render {} samples ({} tail)", total_samples, tail_samples);
+
+        let mut buffer0 = vec![0.0f32; block_size];
+        let mut buffer1 = vec![0.0f32; block_size];
+        let mut output = Vec::<f32>::with_capacity(total_samples * 2);
+
+        let mut note_ids = NoteIds::new();
+        let mut next_event = 0;
+        let mut block_start = 0;
+
+        while block_start < total_samples {
+
+            let block_end = block_start + block_size;
+
+            let mut block_event_count = 0;
+            while next_event < midi_file.events.len() && event_positions[next_event] < block_end {
+                if block_event_count >= MAX_EVENTS_PER_BLOCK {
+                    warn!("too many events in block, deferring to next block");
+                    break;
+                }
+
+                // deferred events get played at the start of the block
+                let sample_offset = event_positions[next_event].saturating_sub(block_start) as i32;
+
+                let message = &midi_file.events[next_event].message;
+                let mut result = Ok(());
+                let is_note = EventList::new_events_from_midi(message, sample_offset, &mut note_ids, |event| {
+                    if result.is_ok() {
+                        result = instrument.push_event(event);
+                        block_event_count += 1;
+                    }
+                });
+                result?;
+
+                if !is_note {
+                    // controllers play as parameter changes
+                    match instrument.map_midi_controller(message) {
+                        Some((id, value)) => {
+                            instrument.set_parameter(id, value, sample_offset)?;
+                        },
+                        None => {}
+                    };
+                }
+
+                next_event += 1;
+            }
+
+            buffer0.fill(0.0);
+            buffer1.fill(0.0);
+
+            let callback_info = AudioCallbackInfo {
+                buffer0: buffer0.as_mut_ptr() as *mut c_void,
+                buffer1: buffer1.as_mut_ptr() as *mut c_void,
+                buffer_size: block_size,
+                sample_format: audio_format.sample_format,
+                time: Instant::now()
+            };
+
+            match instrument.get_context().lock() {
+                Ok(mut context) => {
+                    context.process(&callback_info);
+                },
+                Err(_) => {
+                    return Err(Error::from("failed to lock instrument context"));
+                }
+            };
+
+            // keep the controller in sync, output events are not rendered
+            let _ = instrument.process_outputs();
+
+            let restart_flags = instrument.take_restart_flags();
+            if restart_flags != 0 {
+                instrument.restart_component(restart_flags)?;
+            }
+
+            let frames = block_size.min(total_samples - block_start);
+            for i in 0..frames {
+                output.push(buffer0[i]);
+                output.push(buffer1[i]);
+            }
+
+            block_start = block_end;
+        }
+
+        Ok(output)
+    }
+}
@@ -0,0 +1,154 @@
+//!
+//! Plugin scanner
+//!
+//! Probes plugin libraries for their class information. Out-of-process
+//! scans re-invoke the executable with the `scan` subcommand, so a plugin
+//! crashing or hanging while loading cannot take down the host.
+//!
+
+use log::{*};
+use std::{io::Read, process::{Command, Stdio}, time::{Duration, Instant}};
+
+use crate::{config::REGISTRY_SCAN_TIMEOUT_SECONDS, error::Error, plugin::{get_identifier_from_path, Plugin, PluginInfo}};
+
+pub const SCAN_COMMAND: &str = "scan";
+
+const SCAN_BEGIN_MARKER: &str = "---KEYSTONE-SCAN-BEGIN---";
+const SCAN_END_MARKER: &str = "---KEYSTONE-SCAN-END---";
+const SCAN_ERROR_MARKER: &str = "---KEYSTONE-SCAN-ERROR---";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Scanner {
+}
+
+impl Scanner {
+
+    pub fn scan_in_process(filename: &str) -> Result<PluginInfo, Error> {
+        trace!("scan in process: {}", filename);
+
+        let mut plugin = Plugin::new(filename)?;
+        let plugin_info = plugin.get_info().clone();
+        plugin.dispose();
+
+        Ok(plugin_info)
+    }
+
+    pub fn scan_out_of_process(filename: &str) -> Result<PluginInfo, Error> {
+        trace!("scan out of process: {}", filename);
+
+        let executable = match std::env::current_exe() {
+            Ok(executable) => executable,
+            Err(_) => {
+                return Err(Error::from("failed to locate scanner executable"));
+            }
+        };
+
+        let mut child = match Command::new(executable)
+            .arg(SCAN_COMMAND)
+            .arg(filename)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn() {
+            Ok(child) => child,
+            Err(_) => {
+                return Err(Error::from("failed to start scanner process"));
+            }
+        };
+
+        // drain stdout while waiting, a full pipe would block the child
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        });
+
+        let timeout = Duration::from_secs(REGISTRY_SCAN_TIMEOUT_SECONDS);
+        let start_time = Instant::now();
+
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {
+                    if start_time.elapsed() >= timeout {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        let _ = reader.join();
+                        return Err(Error::from(format!("scan timed out after {} seconds", REGISTRY_SCAN_TIMEOUT_SECONDS)));
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                },
+                Err(_) => {
+                    let _ = child.kill();
+                    let _ = reader.join();
+                    return Err(Error::from("failed to wait for scanner process"));
+                }
+            }
+        };
+
+        let output = reader.join().unwrap_or_default();
+
+        match Self::extract_section(&output, SCAN_ERROR_MARKER, SCAN_END_MARKER) {
+            Some(message) => {
+                return Err(Error::from(message.trim()));
+            },
+            None => {}
+        };
+
+        let section = match Self::extract_section(&output, SCAN_BEGIN_MARKER, SCAN_END_MARKER) {
+            Some(section) => section,
+            None => {
+                return Err(Error::from(format!("scanner process failed ({})", status)));
+            }
+        };
+
+        let mut plugin_info: PluginInfo = match toml::from_str(section) {
+            Ok(plugin_info) => plugin_info,
+            Err(_) => {
+                return Err(Error::from("invalid scanner output"));
+            }
+        };
+
+        plugin_info.id = get_identifier_from_path(filename);
+
+        Ok(plugin_info)
+    }
+
+    /// Entry point of the scanner child process. Prints the plugin info
+    /// to stdout and returns the process exit code.
+    pub fn run_scan_command(filename: &str) -> i32 {
+
+        let scan_result = match Self::scan_in_process(filename) {
+            Ok(plugin_info) => {
+                match toml::to_string(&plugin_info) {
+                    Ok(s) => Ok(s),
+                    Err(_) => Err(Error::from("failed to serialize plugin info"))
+                }
+            },
+            Err(e) => Err(e)
+        };
+
+        match scan_result {
+            Ok(s) => {
+                println!("{}", SCAN_BEGIN_MARKER);
+                print!("{}", s);
+                println!("{}", SCAN_END_MARKER);
+                0
+            },
+            Err(e) => {
+                println!("{}", SCAN_ERROR_MARKER);
+                println!("{}", e.message());
+                println!("{}", SCAN_END_MARKER);
+                1
+            }
+        }
+    }
+
+    fn extract_section<'a>(output: &'a str, begin_marker: &str, end_marker: &str) -> Option<&'a str> {
+        let begin = output.find(begin_marker)? + begin_marker.len();
+        let end = begin + output[begin..].find(end_marker)?;
+        Some(&output[begin..end])
+    }
+}
@@ -0,0 +1,165 @@
+//!
+//! Single producer, single consumer queue
+//!
+//! Fixed capacity lock-free ring buffer for handing data from a control
+//! thread to the audio thread without blocking either side. Exactly one
+//! thread may push and exactly one thread may pop at a time.
+//!
+
+use std::{cell::UnsafeCell, mem::MaybeUninit, sync::atomic::{AtomicUsize, Ordering}};
+
+pub struct SpscQueue<T: Copy> {
+    buffer: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: AtomicUsize, // next slot to read, owned by the consumer
+    tail: AtomicUsize  // next slot to write, owned by the producer
+}
+
+unsafe impl<T: Copy + Send> Send for SpscQueue<T> {}
+unsafe impl<T: Copy + Send> Sync for SpscQueue<T> {}
+
+impl<T: Copy> SpscQueue<T> {
+
+    pub fn new(capacity: usize) -> Self {
+        // one slot stays empty to tell a full queue from an empty one
+        let buffer = (0..capacity + 1).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect::<Vec<_>>();
+        Self {
+            buffer: buffer.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len() - 1
+    }
+
+    /// Appends a value, returns it back if the queue is full. Producer only.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next_tail = self.next_index(tail);
+
+        if next_tail == self.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+
+        unsafe {
+            (*self.buffer[tail].get()).write(value);
+        }
+
+        self.tail.store(next_tail, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Removes the oldest value. Consumer only.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let value = unsafe { (*self.buffer[head].get()).assume_init() };
+
+        self.head.store(self.next_index(head), Ordering::Release);
+
+        Some(value)
+    }
+
+    /// Returns the oldest value without removing it. Consumer only.
+    pub fn peek(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+
+        Some(unsafe { (*self.buffer[head].get()).assume_init() })
+    }
+
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        if tail >= head {
+            tail - head
+        } else {
+            self.buffer.len() - head + tail
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn next_index(&self, index: usize) -> usize {
+        if index + 1 == self.buffer.len() {
+            0
+        } else {
+            index + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn full_and_empty() {
+        let queue = SpscQueue::new(2);
+
+        assert_eq!(queue.capacity(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+
+        assert_eq!(queue.push(1), Ok(()));
+        assert_eq!(queue.push(2), Ok(()));
+        assert_eq!(queue.push(3), Err(3));
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(queue.peek(), Some(1));
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.push(3), Ok(()));
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn concurrent_producer_and_consumer() {
+        const COUNT: u64 = 200_000;
+
+        // small enough that both sides keep running into a full or empty queue
+        let queue = Arc::new(SpscQueue::<u64>::new(16));
+
+        let producer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                for value in 0..COUNT {
+                    let mut value = value;
+                    while let Err(rejected) = queue.push(value) {
+                        value = rejected;
+                        std::thread::yield_now();
+                    }
+                }
+            })
+        };
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match queue.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                },
+                None => {
+                    std::thread::yield_now();
+                }
+            }
+        }
+
+        producer.join().unwrap();
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//!
+//! Plugin state file
+//!
+//! Stores the component and controller state of a plugin together with
+//! the class id and plugin version it was saved from. All integers are
+//! little endian, strings and state blocks are length prefixed:
+//!
+//! ```text
+//! "KSTS" | format version u32 | class id | plugin version | component state | controller state
+//! ```
+//!
+
+use log::{*};
+use std::fs;
+
+use crate::error::Error;
+
+const STATE_FILE_MAGIC: &[u8; 4] = b"KSTS";
+const STATE_FILE_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Default)]
+pub struct PluginState {
+    pub class_id: String,
+    pub plugin_version: String,
+    pub component_state: Vec<u8>,
+    pub controller_state: Vec<u8>
+}
+
+impl PluginState {
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        trace!("save state: {}", path);
+
+        match fs::write(path, self.to_bytes()) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(Error::from(format!("failed to write state file '{}'", path)))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::<u8>::new();
+        data.extend_from_slice(STATE_FILE_MAGIC);
+        data.extend_from_slice(&STATE_FILE_VERSION.to_le_bytes());
+        Self::write_block(&mut data, self.class_id.as_bytes());
+        Self::write_block(&mut data, self.plugin_version.as_bytes());
+        Self::write_block(&mut data, &self.component_state);
+        Self::write_block(&mut data, &self.controller_state);
+        data
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        trace!("load state: {}", path);
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => {
+                return Err(Error::from(format!("failed to read state file '{}'", path)));
+            }
+        };
+
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+
+        if data.len() < 8 || &data[0..4] != STATE_FILE_MAGIC {
+            return Err(Error::from("invalid state file"));
+        }
+
+        let version = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        if version != STATE_FILE_VERSION {
+            return Err(Error::from(format!("unsupported state file version {}", version)));
+        }
+
+        let mut offset = 8;
+        let class_id = Self::read_string(data, &mut offset)?;
+        let plugin_version = Self::read_string(data, &mut offset)?;
+        let component_state = Self::read_block(data, &mut offset)?.to_vec();
+        let controller_state = Self::read_block(data, &mut offset)?.to_vec();
+
+        Ok(Self {
+            class_id,
+            plugin_version,
+            component_state,
+            controller_state
+        })
+    }
+
+    fn write_block(data: &mut Vec<u8>, block: &[u8]) {
+        data.extend_from_slice(&(block.len() as u64).to_le_bytes());
+        data.extend_from_slice(block);
+    }
+
+    fn read_block<'a>(data: &'a [u8], offset: &mut usize) -> Result<&'a [u8], Error> {
+        if data.len() < *offset + 8 {
+            return Err(Error::from("truncated state file"));
+        }
+
+        let mut length_bytes = [0u8; 8];
+        length_bytes.copy_from_slice(&data[*offset..*offset + 8]);
+        let length = u64::from_le_bytes(length_bytes) as usize;
+        *offset += 8;
+
+        if data.len() - *offset < length {
+            return Err(Error::from("truncated state file"));
+        }
+
+        let block = &data[*offset..*offset + length];
+        *offset += length;
+
+        Ok(block)
+    }
+
+    fn read_string(data: &[u8], offset: &mut usize) -> Result<String, Error> {
+        let block = Self::read_block(data, offset)?;
+        match String::from_utf8(block.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(Error::from("invalid string in state file"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> PluginState {
+        PluginState {
+            class_id: "0123456789ABCDEF0123456789ABCDEF".to_string(),
+            plugin_version: "1.2.0".to_string(),
+            component_state: vec![1, 2, 3, 4],
+            controller_state: vec![5, 6]
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = state();
+        let parsed = PluginState::parse(&original.to_bytes()).unwrap();
+
+        assert_eq!(parsed.class_id, original.class_id);
+        assert_eq!(parsed.plugin_version, original.plugin_version);
+        assert_eq!(parsed.component_state, original.component_state);
+        assert_eq!(parsed.controller_state, original.controller_state);
+    }
+
+    #[test]
+    fn empty_states() {
+        let parsed = PluginState::parse(&PluginState::default().to_bytes()).unwrap();
+
+        assert!(parsed.class_id.is_empty());
+        assert!(parsed.component_state.is_empty());
+        assert!(parsed.controller_state.is_empty());
+    }
+
+    #[test]
+    fn truncated_data() {
+        let data = state().to_bytes();
+        for len in 0..data.len() {
+            assert!(PluginState::parse(&data[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut data = state().to_bytes();
+        data[0] = b'X';
+        assert!(PluginState::parse(&data).is_err());
+
+        let mut data = state().to_bytes();
+        data[4..8].copy_from_slice(&(STATE_FILE_VERSION + 1).to_le_bytes());
+        assert!(PluginState::parse(&data).is_err());
+    }
+
+    #[test]
+    fn oversized_block() {
+        let mut data = state().to_bytes();
+        data[8..16].copy_from_slice(&u64::MAX.to_le_bytes()); // class id length
+        assert!(PluginState::parse(&data).is_err());
+    }
+
+    #[test]
+    fn invalid_string() {
+        let mut data = state().to_bytes();
+        data[16] = 0xFF; // first byte of the class id
+        assert!(PluginState::parse(&data).is_err());
+    }
+}
@@ -1,18 +1,62 @@
+//!
+//! Memory stream
+//!
+//! Growable in-memory `IBStream` used to exchange component and
+//! controller state with plugins.
+//!
 
 use log::{*};
-use std::ptr::null_mut;
+use std::{ptr::null_mut, sync::Mutex};
 
-use vst3_sys::{base::{kResultFalse, tresult, IBStream, IBStreamVTable}, utils::SharedVstPtr, VST3};
+use vst3_sys::{base::{kIBSeekCur, kIBSeekEnd, kIBSeekSet, kInvalidArgument, kResultOk, tresult, IBStream, IBStreamVTable, ISizeableStream}, utils::SharedVstPtr, VST3};
 
+/// Streams are addressed with `int32` byte counts, positions beyond that are rejected.
+const MAX_POSITION: i64 = i32::MAX as i64;
 
-#[VST3(implements(IBStream))]
+struct StreamBuffer {
+    data: Vec<u8>,
+    position: usize
+}
+
+#[VST3(implements(IBStream, ISizeableStream))]
 pub struct ByteStream {
+    buffer: Mutex<StreamBuffer>
 }
 
 impl ByteStream {
     pub fn new() -> Box<Self> {
-        let instance = Self::allocate();
-        instance
+        Self::from_vec(Vec::new())
+    }
+
+    /// Creates a stream reading the given data from the start.
+    pub fn from_vec(data: Vec<u8>) -> Box<Self> {
+        Self::allocate(Mutex::new(StreamBuffer {
+            data,
+            position: 0
+        }))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().data.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.lock().unwrap().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.data.clear();
+        buffer.position = 0;
+    }
+
+    /// Moves the position back to the start, e.g. to read back written state.
+    pub fn rewind(&mut self) {
+        self.buffer.lock().unwrap().position = 0;
     }
 
     pub fn get_shared_ptr(&mut self) -> SharedVstPtr<dyn IBStream> {
@@ -32,23 +76,228 @@ impl ByteStream {
 }
 
 impl IBStream for ByteStream {
-    unsafe fn read(&self, _buffer: *mut std::ffi::c_void, num_bytes: i32, _num_bytes_read: *mut i32) -> tresult {
+    unsafe fn read(&self, buffer: *mut std::ffi::c_void, num_bytes: i32, num_bytes_read: *mut i32) -> tresult {
         trace!("stream::read {} bytes", num_bytes);
-        kResultFalse
+
+        if buffer.is_null() || num_bytes < 0 {
+            return kInvalidArgument;
+        }
+
+        let mut stream_buffer = self.buffer.lock().unwrap();
+
+        // reading past the end is not an error, it just returns fewer bytes
+        let available = stream_buffer.data.len().saturating_sub(stream_buffer.position);
+        let count = available.min(num_bytes as usize);
+
+        if count > 0 {
+            let source = stream_buffer.data.as_ptr().add(stream_buffer.position);
+            std::ptr::copy_nonoverlapping(source, buffer as *mut u8, count);
+            stream_buffer.position += count;
+        }
+
+        if !num_bytes_read.is_null() {
+            *num_bytes_read = count as i32;
+        }
+
+        kResultOk
     }
 
-    unsafe fn write(&self, _buffer: *const std::ffi::c_void, num_bytes: i32, _num_bytes_written: *mut i32,) -> tresult {
+    unsafe fn write(&self, buffer: *const std::ffi::c_void, num_bytes: i32, num_bytes_written: *mut i32,) -> tresult {
         trace!("stream::write {} bytes", num_bytes);
-        kResultFalse
+
+        if buffer.is_null() || num_bytes < 0 {
+            return kInvalidArgument;
+        }
+
+        let mut stream_buffer = self.buffer.lock().unwrap();
+
+        let count = num_bytes as usize;
+        let start = stream_buffer.position;
+        let end = match start.checked_add(count) {
+            Some(end) if end as u64 <= MAX_POSITION as u64 => end,
+            _ => {
+                return kInvalidArgument;
+            }
+        };
+
+        // writing after a seek past the end fills the gap with zeros
+        if end > stream_buffer.data.len() {
+            stream_buffer.data.resize(end, 0);
+        }
+
+        if count > 0 {
+            let source = std::slice::from_raw_parts(buffer as *const u8, count);
+            stream_buffer.data[start..end].copy_from_slice(source);
+        }
+
+        stream_buffer.position = end;
+
+        if !num_bytes_written.is_null() {
+            *num_bytes_written = num_bytes;
+        }
+
+        kResultOk
     }
 
-    unsafe fn seek(&self, pos: i64, mode: i32, _result: *mut i64) -> tresult {
+    unsafe fn seek(&self, pos: i64, mode: i32, result: *mut i64) -> tresult {
         trace!("stream::seek pos:{}, mode:{}", pos, mode);
-        kResultFalse
+
+        let mut stream_buffer = self.buffer.lock().unwrap();
+
+        let base = match mode {
+            kIBSeekSet => 0,
+            kIBSeekCur => stream_buffer.position as i64,
+            kIBSeekEnd => stream_buffer.data.len() as i64,
+            _ => {
+                return kInvalidArgument;
+            }
+        };
+
+        let position = match base.checked_add(pos) {
+            Some(position) if (0..=MAX_POSITION).contains(&position) => position,
+            _ => {
+                return kInvalidArgument;
+            }
+        };
+
+        stream_buffer.position = position as usize;
+
+        if !result.is_null() {
+            *result = position;
+        }
+
+        kResultOk
     }
 
-    unsafe fn tell(&self, _pos: *mut i64) -> tresult {
+    unsafe fn tell(&self, pos: *mut i64) -> tresult {
         trace!("stream::tell");
-        kResultFalse
+
+        if pos.is_null() {
+            return kInvalidArgument;
+        }
+
+        *pos = self.buffer.lock().unwrap().position as i64;
+
+        kResultOk
+    }
+}
+
+impl ISizeableStream for ByteStream {
+    unsafe fn get_stream_size(&self, size: *mut i64) -> tresult {
+        trace!("stream::get stream size");
+
+        if size.is_null() {
+            return kInvalidArgument;
+        }
+
+        *size = self.buffer.lock().unwrap().data.len() as i64;
+
+        kResultOk
+    }
+
+    unsafe fn set_stream_size(&self, size: i64) -> tresult {
+        trace!("stream::set stream size {}", size);
+
+        if !(0..=MAX_POSITION).contains(&size) {
+            return kInvalidArgument;
+        }
+
+        let mut stream_buffer = self.buffer.lock().unwrap();
+        stream_buffer.data.resize(size as usize, 0);
+
+        kResultOk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(stream: &ByteStream, data: &[u8]) -> (tresult, i32) {
+        let mut written = -1;
+        let result = unsafe {
+            stream.write(data.as_ptr() as *const _, data.len() as i32, &mut written)
+        };
+        (result, written)
+    }
+
+    fn read(stream: &ByteStream, count: usize) -> (tresult, Vec<u8>) {
+        let mut data = vec![0u8; count];
+        let mut read = -1;
+        let result = unsafe {
+            stream.read(data.as_mut_ptr() as *mut _, count as i32, &mut read)
+        };
+        data.truncate(read.max(0) as usize);
+        (result, data)
+    }
+
+    fn seek(stream: &ByteStream, pos: i64, mode: i32) -> (tresult, i64) {
+        let mut position = -1;
+        let result = unsafe {
+            stream.seek(pos, mode, &mut position)
+        };
+        (result, position)
+    }
+
+    #[test]
+    fn seek_modes() {
+        let stream = ByteStream::from_vec(vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        assert_eq!(seek(&stream, 3, kIBSeekSet), (kResultOk, 3));
+        assert_eq!(seek(&stream, 2, kIBSeekCur), (kResultOk, 5));
+        assert_eq!(seek(&stream, -1, kIBSeekCur), (kResultOk, 4));
+        assert_eq!(seek(&stream, -2, kIBSeekEnd), (kResultOk, 6));
+        assert_eq!(read(&stream, 1), (kResultOk, vec![6]));
+
+        let mut position = -1;
+        assert_eq!(unsafe { stream.tell(&mut position) }, kResultOk);
+        assert_eq!(position, 7);
+    }
+
+    #[test]
+    fn seek_rejects_invalid_positions() {
+        let stream = ByteStream::from_vec(vec![0; 4]);
+
+        assert_eq!(seek(&stream, -1, kIBSeekSet).0, kInvalidArgument);
+        assert_eq!(seek(&stream, -5, kIBSeekEnd).0, kInvalidArgument);
+        assert_eq!(seek(&stream, MAX_POSITION + 1, kIBSeekSet).0, kInvalidArgument);
+        assert_eq!(seek(&stream, i64::MAX, kIBSeekEnd).0, kInvalidArgument);
+        assert_eq!(seek(&stream, 0, 3).0, kInvalidArgument);
+
+        // a failed seek keeps the previous position
+        assert_eq!(seek(&stream, 0, kIBSeekCur), (kResultOk, 0));
+    }
+
+    #[test]
+    fn read_past_end() {
+        let stream = ByteStream::from_vec(vec![1, 2, 3]);
+
+        assert_eq!(read(&stream, 8), (kResultOk, vec![1, 2, 3]));
+        assert_eq!(read(&stream, 8), (kResultOk, vec![]));
+
+        assert_eq!(seek(&stream, 10, kIBSeekSet), (kResultOk, 10));
+        assert_eq!(read(&stream, 1), (kResultOk, vec![]));
+    }
+
+    #[test]
+    fn write_after_seek_past_end_fills_gap() {
+        let mut stream = ByteStream::new();
+
+        assert_eq!(write(&stream, &[1, 2]), (kResultOk, 2));
+        assert_eq!(seek(&stream, 2, kIBSeekCur), (kResultOk, 4));
+        assert_eq!(write(&stream, &[5]), (kResultOk, 1));
+        assert_eq!(stream.to_vec(), vec![1, 2, 0, 0, 5]);
+
+        stream.rewind();
+        assert_eq!(read(&stream, 2), (kResultOk, vec![1, 2]));
+    }
+
+    #[test]
+    fn write_rejects_overflowing_end() {
+        let stream = ByteStream::new();
+
+        assert_eq!(seek(&stream, MAX_POSITION, kIBSeekSet), (kResultOk, MAX_POSITION));
+        assert_eq!(write(&stream, &[1]).0, kInvalidArgument);
+        assert!(stream.is_empty());
     }
 }
@@ -0,0 +1,332 @@
+//!
+//! Stress test
+//!
+//! Plays random notes and SysEx messages from several threads at once while
+//! the null audio backend runs. Afterwards every event sent must have been delivered to
+//! the processor or counted as dropped.
+//!
+
+use log::{*};
+use std::{sync::atomic::Ordering, thread::JoinHandle, time::{Duration, Instant}};
+use vst3_sys::vst::EventTypes;
+
+use crate::{config::{STRESS_EVENTS_PER_SECOND, STRESS_RUN_TIME_SECONDS, STRESS_THREADS}, error::Error, events::{EventList, EventSender, NoteIds}, instrument::{Instrument, ProcessStats}, midi::MIDI_CHANNEL_COUNT};
+
+const BURST_INTERVAL_MS: u64 = 1;
+const SYSEX_INTERVAL: u64 = 16; // every nth event is a SysEx message
+const SYSEX_MESSAGE: [u8; 8] = [0xF0, 0x7D, 0x01, 0x02, 0x03, 0x04, 0x05, 0xF7]; // non-commercial ID
+
+pub struct StressSettings {
+    pub class_id: String,
+    pub threads: usize,
+    pub run_time: Duration,
+    pub events_per_second: u32 // per thread
+}
+
+impl StressSettings {
+    pub fn new(class_id: &str) -> Self {
+        Self {
+            class_id: class_id.to_string(),
+            threads: STRESS_THREADS,
+            run_time: Duration::from_secs(STRESS_RUN_TIME_SECONDS),
+            events_per_second: STRESS_EVENTS_PER_SECOND
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProducerCount {
+    pub sent: u64,
+    pub rejected: u64 // event queue full
+}
+
+pub struct StressTest {
+    producers: Vec<JoinHandle<ProducerCount>>
+}
+
+impl StressTest {
+    /// Starts the producer threads, they stop by themselves after the run time.
+    pub fn start(instrument: &Instrument, settings: &StressSettings, buffer_size: usize) -> Result<Self, Error> {
+        trace!("start {} producers", settings.threads);
+
+        // claim all queues first, so nothing runs if there are not enough
+        let mut senders = Vec::new();
+        for _ in 0..settings.threads {
+            senders.push(instrument.create_event_sender()?);
+        }
+
+        Self::start_with_senders(senders, settings, buffer_size)
+    }
+
+    /// Starts one producer thread per sender.
+    pub fn start_with_senders(senders: Vec<EventSender>, settings: &StressSettings, buffer_size: usize) -> Result<Self, Error> {
+        let mut producers = Vec::new();
+
+        for (index, sender) in senders.into_iter().enumerate() {
+            let run_time = settings.run_time;
+            let events_per_second = settings.events_per_second;
+
+            let producer = match std::thread::Builder::new()
+                .name(format!("stress-{}", index))
+                .spawn(move || Self::run_producer(sender, index as u32 + 1, run_time, events_per_second, buffer_size)) {
+                Ok(producer) => producer,
+                Err(_) => {
+                    return Err(Error::from("failed to start stress producer thread"));
+                }
+            };
+
+            producers.push(producer);
+        }
+
+        Ok(Self {
+            producers
+        })
+    }
+
+    /// Waits for the producers and returns their combined count.
+    pub fn join(self) -> Result<ProducerCount, Error> {
+        let mut total = ProducerCount::default();
+
+        for producer in self.producers {
+            match producer.join() {
+                Ok(count) => {
+                    total.sent += count.sent;
+                    total.rejected += count.rejected;
+                },
+                Err(_) => {
+                    return Err(Error::from("stress producer thread panicked"));
+                }
+            };
+        }
+
+        Ok(total)
+    }
+
+    /// Logs the result, fails if events got lost between sender and processor.
+    pub fn report(count: &ProducerCount, stats: &ProcessStats, block_time: Duration) -> Result<(), Error> {
+        let delivered = stats.events.load(Ordering::Relaxed);
+        let dropped = stats.dropped_events.load(Ordering::Relaxed);
+
+        debug!("stress: sent {} events, {} rejected by full queues", count.sent, count.rejected);
+        debug!("stress: delivered {} events, {} dropped by full event lists", delivered, dropped);
+        debug!("stress: {} blocks, {} skipped, {} failed, max process time {} us of {} us",
+            stats.blocks.load(Ordering::Relaxed),
+            stats.skipped_blocks.load(Ordering::Relaxed),
+            stats.failed_blocks.load(Ordering::Relaxed),
+            stats.max_process_time_us.load(Ordering::Relaxed),
+            block_time.as_micros());
+
+        let accepted = count.sent - count.rejected;
+        if delivered + dropped != accepted {
+            return Err(Error::from(format!("stress: {} events accepted but {} arrived", accepted, delivered + dropped)));
+        }
+
+        Ok(())
+    }
+
+    fn run_producer(sender: EventSender, seed: u32, run_time: Duration, events_per_second: u32, buffer_size: usize) -> ProducerCount {
+        let mut count = ProducerCount::default();
+        let mut random = Random::new(seed);
+        let mut held_pitch: Option<u8> = None;
+        let mut note_ids = NoteIds::new();
+        let channel = (seed % MIDI_CHANNEL_COUNT as u32) as u8;
+
+        let burst_interval = Duration::from_millis(BURST_INTERVAL_MS);
+        let burst_size = (events_per_second as u64 * BURST_INTERVAL_MS / 1000).max(1);
+        let end_time = Instant::now() + run_time;
+
+        while Instant::now() < end_time {
+            for _ in 0..burst_size {
+                // offsets up to the end of the following block
+                let sample_offset = (random.next() as usize % (buffer_size * 2)) as i32;
+
+                count.sent += 1;
+
+                if count.sent % SYSEX_INTERVAL == 0 {
+                    let event = EventList::new_event(EventList::new_sysex_event(), EventTypes::kDataEvent, sample_offset);
+                    if sender.send_with_payload(event, &SYSEX_MESSAGE).is_err() {
+                        count.rejected += 1;
+                    }
+                    continue;
+                }
+
+                let event = match held_pitch.take() {
+                    Some(pitch) => {
+                        let note_id = note_ids.note_off(channel, pitch);
+                        EventList::new_event(EventList::new_note_off_event(channel as i16, pitch as i16, 0.0, 0.0, note_id), EventTypes::kNoteOffEvent, sample_offset)
+                    },
+                    None => {
+                        let pitch = 36 + (random.next() % 48) as u8;
+                        held_pitch = Some(pitch);
+                        let (note_id, _) = note_ids.note_on(channel, pitch); // the previous note was released
+                        EventList::new_event(EventList::new_note_on_event(channel as i16, pitch as i16, 0.0, 0.5, 0, note_id), EventTypes::kNoteOnEvent, sample_offset)
+                    }
+                };
+
+                if sender.send(event).is_err() {
+                    count.rejected += 1;
+                }
+            }
+
+            std::thread::sleep(burst_interval);
+        }
+
+        count
+    }
+}
+
+/// Xorshift generator, good enough for picking notes.
+struct Random {
+    state: u32
+}
+
+impl Random {
+    fn new(seed: u32) -> Self {
+        Self {
+            state: seed.max(1)
+        }
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::{audio::{AudioBackend, AudioCallbackInfo}, audio_null::NullAudio, events::{EventSlots, InputEvents, PayloadPool, NO_NOTE_ID}};
+
+    const SAMPLE_RATE: f64 = 48000.0;
+    const BUFFER_SIZE: usize = 64;
+    const SENDER_COUNT: usize = 4;
+    const QUEUE_SIZE: usize = 256;
+
+    fn create_slots() -> EventSlots {
+        EventSlots::new(SENDER_COUNT, QUEUE_SIZE, Arc::new(PayloadPool::new(16, 1024)))
+    }
+
+    /// Runs the null backend with a callback that dequeues and finishes
+    /// blocks the way the instrument context does, `on_block` sees the
+    /// events of each block.
+    fn run_audio<F: FnMut(&EventList) + Send + 'static>(mut input_events: InputEvents, stats: Arc<ProcessStats>, run_time: Duration, mut on_block: F) {
+        let mut audio = NullAudio::open("null", SAMPLE_RATE, BUFFER_SIZE).unwrap();
+
+        audio.start(Box::new(move |callback_info: &AudioCallbackInfo| {
+            input_events.dequeue(callback_info.buffer_size, &stats);
+            on_block(input_events.get_current());
+            input_events.finish_block();
+            stats.blocks.fetch_add(1, Ordering::Relaxed);
+        })).unwrap();
+
+        std::thread::sleep(run_time);
+        audio.stop().unwrap();
+    }
+
+    fn note_on(sample_offset: i32, pitch: i16) -> vst3_sys::vst::Event {
+        EventList::new_event(EventList::new_note_on_event(0, pitch, 0.0, 0.5, 0, NO_NOTE_ID), EventTypes::kNoteOnEvent, sample_offset)
+    }
+
+    #[test]
+    fn senders_claim_and_release_queues() {
+        let slots = create_slots();
+
+        let mut senders = Vec::new();
+        for _ in 0..SENDER_COUNT {
+            senders.push(slots.claim().unwrap());
+        }
+        assert!(slots.claim().is_err());
+
+        // dropping a sender frees its queue for the next one
+        senders.remove(1);
+        senders.push(slots.claim().unwrap());
+        assert!(slots.claim().is_err());
+
+        senders.clear();
+        for _ in 0..SENDER_COUNT {
+            senders.push(slots.claim().unwrap());
+        }
+    }
+
+    #[test]
+    fn events_beyond_block_go_to_next_block() {
+        let slots = create_slots();
+        let sender = slots.claim().unwrap();
+        let stats = Arc::new(ProcessStats::default());
+
+        let block_size = BUFFER_SIZE as i32;
+        for (sample_offset, pitch) in [(block_size + 3, 62), (0, 60), (3 * block_size, 63), (10, 61), (-5, 59)] {
+            sender.send(note_on(sample_offset, pitch)).unwrap();
+        }
+
+        let blocks = Arc::new(Mutex::new(Vec::<Vec<(i32, i16)>>::new()));
+        let received = blocks.clone();
+        run_audio(slots.create_input_events(), stats.clone(), Duration::from_millis(50), move |event_list| {
+            let mut block = Vec::new();
+            event_list.for_each_event(|event| {
+                block.push((event.sample_offset, unsafe { event.event.note_on.pitch }));
+            });
+            received.lock().unwrap().push(block);
+        });
+
+        let blocks = blocks.lock().unwrap();
+        assert!(blocks.len() >= 3);
+        // sorted by offset and stable, negative offsets start the block, late ones end the next block
+        assert_eq!(blocks[0], vec![(0, 60), (0, 59), (10, 61)]);
+        assert_eq!(blocks[1], vec![(3, 62), (block_size - 1, 63)]);
+        assert!(blocks[2..].iter().all(|block| block.is_empty()));
+        assert_eq!(stats.events.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn concurrent_senders_deliver_every_event() {
+        let slots = create_slots();
+        let stats = Arc::new(ProcessStats::default());
+
+        let settings = StressSettings {
+            class_id: String::new(),
+            threads: SENDER_COUNT,
+            run_time: Duration::from_millis(300),
+            events_per_second: 5000
+        };
+
+        let mut senders = Vec::new();
+        for _ in 0..settings.threads {
+            senders.push(slots.claim().unwrap());
+        }
+
+        let stress_test = StressTest::start_with_senders(senders, &settings, BUFFER_SIZE).unwrap();
+
+        let block_size = BUFFER_SIZE as i32;
+        let sysex_count = Arc::new(Mutex::new(0u64));
+        let received = sysex_count.clone();
+        run_audio(slots.create_input_events(), stats.clone(), settings.run_time + Duration::from_millis(200), move |event_list| {
+            event_list.for_each_event(|event| {
+                assert!((0..block_size).contains(&event.sample_offset));
+                match EventList::get_payload(event) {
+                    Some((ptr, size)) => {
+                        assert_eq!(unsafe { std::slice::from_raw_parts(ptr, size) }, &SYSEX_MESSAGE);
+                        *received.lock().unwrap() += 1;
+                    },
+                    None => {}
+                };
+            });
+        });
+
+        let count = stress_test.join().unwrap();
+        assert!(count.sent > 0);
+        assert!(*sysex_count.lock().unwrap() > 0);
+        assert!(StressTest::report(&count, &stats, Duration::from_secs_f64(BUFFER_SIZE as f64 / SAMPLE_RATE)).is_ok());
+
+        // all senders are gone, their queues can be claimed again
+        for _ in 0..SENDER_COUNT {
+            assert!(slots.claim().is_ok());
+        }
+    }
+}
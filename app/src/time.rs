@@ -2,7 +2,7 @@
 //! Time
 //!
 
-use std::sync::{Arc, Mutex};
+use std::{sync::{Arc, Mutex}, time::Instant};
 
 use crate::error::Error;
 
@@ -38,6 +38,16 @@ impl TimingContext {
     }
 }
 
+/// Position of `time` relative to `block_time` in samples, negative for
+/// times before the block start.
+pub fn sample_offset_from_time(time: Instant, block_time: Instant, sample_rate: f64) -> i64 {
+    if time >= block_time {
+        ((time - block_time).as_secs_f64() * sample_rate) as i64
+    } else {
+        -(((block_time - time).as_secs_f64() * sample_rate).ceil() as i64)
+    }
+}
+
 pub struct Timing {
 }
 
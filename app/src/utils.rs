@@ -2,7 +2,7 @@ use log::{*};
 use vst3_sys::base::IUnknown;
 use std::{fs, time::UNIX_EPOCH};
 
-use vst3_com::{ComInterface, VstPtr};
+use vst3_com::{sys::GUID, ComInterface, VstPtr};
 
 
 pub fn get_file_time(filename: &str) -> i64 {
@@ -130,3 +130,56 @@ pub fn trace_ref<T: ComInterface + ?Sized>(ptr: &VstPtr<T>) {
         trace!("[[ref count: {}]]", ref_count);
     }
 }
+
+pub trait GuidStringify {
+    fn to_string(&self) -> String;
+    fn from_string(s: &str) -> GUID;
+}
+
+impl GuidStringify for GUID {
+
+    fn to_string(&self) -> String {
+        self.data.iter().map(|n| format!("{:02X?}", n) ).collect::<Vec<String>>().join("")
+    }
+
+    fn from_string(s: &str) -> Self {
+
+        let mut data: [u8; 16] = [0; 16];
+
+        if !s.is_empty() && s.len() <= 32 {
+            let mut low_nibble = false;
+            let mut byte_index = 0;
+            let mut accumulator: u8 = 0x0;
+            for c in s.chars() {
+
+                accumulator <<= 4;
+                accumulator += c.to_digit(16).unwrap_or(0) as u8;
+
+                if low_nibble {
+                    data[byte_index] = accumulator;
+                    byte_index += 1;
+                    accumulator = 0x0;
+                }
+                low_nibble = !low_nibble;
+            }
+        }
+
+        Self {
+            data
+        }
+
+    }
+
+}
+
+/// Converts a zero terminated char8 buffer to a string.
+pub fn string_from_char8(buffer: &[std::os::raw::c_char]) -> String {
+    let bytes: Vec<u8> = buffer.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Converts a zero terminated char16 (UTF-16) buffer to a string.
+pub fn string_from_char16(buffer: &[i16]) -> String {
+    let units: Vec<u16> = buffer.iter().take_while(|c| **c != 0).map(|c| *c as u16).collect();
+    String::from_utf16_lossy(&units)
+}
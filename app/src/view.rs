@@ -3,13 +3,29 @@ use std::{cell::RefCell, ffi::c_void, ptr::null_mut};
 use log::{*};
 use vst3_com::VstPtr;
 use vst3_sys::{base::{kResultOk, kResultTrue, tresult, IUnknown}, gui::{IPlugFrame, IPlugFrameVTable, IPlugView, IPlugViewContentScaleSupport, ViewRect}, utils::SharedVstPtr, VST3};
-use windows_sys::Win32::{Foundation::HWND, UI::WindowsAndMessaging::PostMessageA};
-
-use crate::{constants::WM_USER_VIEW_RESIZE, error::Error, utils::Size};
+#[cfg(windows)]
+use windows_sys::Win32::UI::WindowsAndMessaging::PostMessageA;
+
+#[cfg(windows)]
+use crate::{constants::WM_USER_VIEW_RESIZE, host::Host};
+use crate::{error::Error, utils::Size};
+
+#[cfg(windows)]
+const PLATFORM_TYPE: &str = "HWND\0";
+#[cfg(not(windows))]
+const PLATFORM_TYPE: &str = "X11EmbedWindowID\0";
+
+/// Views are only opened in a window on Windows, other platforms do not
+/// announce them.
+#[cfg(windows)]
+pub fn register_interfaces(host: &Host) {
+    host.register_interface_support::<dyn IPlugView>();
+    host.register_interface_support::<dyn IPlugFrame>();
+}
 
 #[VST3(implements(IPlugFrame))]
 pub struct PlugFrame {
-    hwnd: HWND,
+    hwnd: *mut c_void,
     recursion_guard: RefCell<bool>
 }
 
@@ -40,7 +56,7 @@ impl PlugFrame {
         return ptr
     }
 
-    pub fn attach(&mut self, hwnd: HWND) {
+    pub fn attach(&mut self, hwnd: *mut c_void) {
         self.hwnd = hwnd;
     }
 
@@ -65,10 +81,11 @@ impl IPlugFrame for PlugFrame {
         view.get_size(&mut size_rect);
         //trace!("resize view {:?}", size_rect);
 
-        let width = size_rect.right - size_rect.left;
-        let height = size_rect.bottom - size_rect.top;
-
+        #[cfg(windows)]
         if !self.hwnd.is_null() {
+            let width = size_rect.right - size_rect.left;
+            let height = size_rect.bottom - size_rect.top;
+
             unsafe {
                 PostMessageA(self.hwnd, WM_USER_VIEW_RESIZE, width as usize, height as isize);
             }
@@ -126,8 +143,7 @@ impl View {
 
     pub fn attach(&mut self, hwnd: *mut c_void) -> Result<(), Error> {
         trace!("attached");
-        let view_type = "HWND\0";
-        let view_type_ptr = view_type.as_ptr();
+        let view_type_ptr = PLATFORM_TYPE.as_ptr();
         let result = unsafe { self.plug_view.attached(hwnd, view_type_ptr as *const i8) };
         if result != kResultOk {
             return Err(Error::from("failed to attach view to window"));
@@ -0,0 +1,60 @@
+//!
+//! WAV file writer
+//!
+
+use log::{*};
+use std::{fs::File, io::{BufWriter, Write}};
+
+use crate::error::Error;
+
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Writes interleaved 32-bit float samples as IEEE float WAV file.
+pub fn write_wav_float(path: &str, sample_rate: u32, num_channels: u16, samples: &[f32]) -> Result<(), Error> {
+    trace!("write wav file: {}", path);
+
+    let file = match File::create(path) {
+        Ok(f) => f,
+        Err(_) => {
+            return Err(Error::from(format!("failed to create wav file '{}'", path)));
+        }
+    };
+
+    let mut writer = BufWriter::new(file);
+
+    let bytes_per_sample: u16 = 4;
+    let block_align = num_channels * bytes_per_sample;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_size = (samples.len() * bytes_per_sample as usize) as u32;
+
+    let mut header = Vec::<u8>::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&WAVE_FORMAT_IEEE_FLOAT.to_le_bytes());
+    header.extend_from_slice(&num_channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+
+    if writer.write_all(&header).is_err() {
+        return Err(Error::from("failed to write wav file"));
+    }
+
+    for sample in samples {
+        if writer.write_all(&sample.to_le_bytes()).is_err() {
+            return Err(Error::from("failed to write wav file"));
+        }
+    }
+
+    if writer.flush().is_err() {
+        return Err(Error::from("failed to write wav file"));
+    }
+
+    Ok(())
+}
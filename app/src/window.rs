@@ -183,14 +183,24 @@ impl Window {
         Ok(())
     }
 
-    pub fn event_loop(&self) {
+    /// Runs the message loop, `on_idle` is called every `idle_interval`
+    /// from a thread timer.
+    pub fn event_loop<F: FnMut()>(&self, idle_interval: Duration, mut on_idle: F) {
         trace!("event loop");
         unsafe {
+            let timer_id = SetTimer(core::ptr::null_mut(), 0, idle_interval.as_millis() as u32, None);
+
             let mut message = std::mem::zeroed();
             while GetMessageA(&mut message, core::ptr::null_mut(), 0, 0) != 0 {
+                if message.message == WM_TIMER && message.hwnd.is_null() {
+                    on_idle();
+                    continue;
+                }
                 TranslateMessage(&message);
                 DispatchMessageA(&message);
             }
+
+            KillTimer(core::ptr::null_mut(), timer_id);
         }
     }
 
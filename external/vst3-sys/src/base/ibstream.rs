@@ -18,3 +18,9 @@ pub trait IBStream: IUnknown {
     unsafe fn seek(&self, pos: i64, mode: i32, result: *mut i64) -> tresult;
     unsafe fn tell(&self, pos: *mut i64) -> tresult;
 }
+
+#[com_interface("04F9549E-E02F-4E6E-87E8-6A8747F4E17F")]
+pub trait ISizeableStream: IUnknown {
+    unsafe fn get_stream_size(&self, size: *mut i64) -> tresult;
+    unsafe fn set_stream_size(&self, size: i64) -> tresult;
+}